@@ -0,0 +1,284 @@
+use std::io::Cursor;
+use std::path::Path;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use serde::{Serialize, Deserialize};
+
+use aoe4_villager_enforcer::detector::SearchRegion;
+use aoe4_villager_enforcer::error::{Error, Result};
+
+use crate::config::{self, AlertSettings, Config, Needle, Settings};
+
+/// Identifies bundle files, in case someone imports another JSON file
+const FORMAT: &str = "aoe4-villager-enforcer-profile";
+const VERSION: u32 = 2;
+
+/// A profile in a single file, to share it with other players
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct Bundle {
+  format: String,
+  version: u32,
+  name: String,
+  metadata: Metadata,
+  window_title: String,
+  needles: Vec<BundleNeedle>,
+  search: SearchRegion,
+  reference_height: Option<u32>,
+  scales: Vec<f32>,
+  alert: AlertSettings,
+  #[serde(default)]
+  settings: Settings,
+}
+
+/// Informative only, to know what the profile was made for before importing it
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct Metadata {
+  civs: Vec<String>,
+  /// Window height the villager images were taken at
+  resolution: Option<u32>,
+  ui_scales: Vec<f32>,
+  app_version: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct BundleNeedle {
+  civ: String,
+  /// Base64 encoded PNG
+  png: String,
+}
+
+/// Writes `config` and its villager images to a bundle file
+pub fn export(config: &Config, path: &Path) -> Result<()> {
+  let needles = config.needles
+    .iter()
+    .map(|needle| {
+      let mut png = vec![];
+      needle.data
+        .write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png)?;
+      Ok(BundleNeedle { civ: needle.civ.clone(), png: BASE64.encode(png) })
+    })
+    .collect::<Result<Vec<_>>>()?;
+
+  let bundle = Bundle {
+    format: FORMAT.to_owned(),
+    version: VERSION,
+    name: config.name.clone(),
+    metadata: Metadata {
+      civs: config.needles.iter().map(|needle| needle.civ.clone()).collect(),
+      resolution: config.reference_height,
+      ui_scales: config.scales.clone(),
+      app_version: env!("CARGO_PKG_VERSION").to_owned(),
+    },
+    window_title: config.window_title.clone(),
+    needles,
+    search: config.search,
+    reference_height: config.reference_height,
+    scales: config.scales.clone(),
+    alert: config.alert.clone(),
+    settings: config.settings.clone(),
+  };
+
+  return Ok(std::fs::write(path, serde_json::to_string_pretty(&bundle)?)?);
+}
+
+/// Reads and validates a bundle file, returns the profile it contains. It
+/// isn't installed until it's written with `config::write`.
+pub fn read(path: &Path) -> Result<Config> {
+  let content = std::fs::read_to_string(path)?;
+  let mut value = serde_json::from_str::<serde_json::Value>(&content)
+    .map_err(|err| Error::invalid(path, format!("not a profile bundle: {}", err)))?;
+
+  if value.get("format").and_then(|format| format.as_str()) != Some(FORMAT) {
+    return Err(Error::invalid(path, "not a profile bundle"));
+  }
+  // Version 1 bundles stored y_max like version 2 profiles
+  if value.get("version").and_then(|version| version.as_u64()) == Some(1) {
+    config::migrate_search_region(&mut value);
+    value["version"] = VERSION.into();
+  }
+
+  let bundle = serde_json::from_value::<Bundle>(value)
+    .map_err(|err| Error::invalid(path, format!("not a profile bundle: {}", err)))?;
+  if bundle.version != VERSION {
+    return Err(Error::invalid(path, format!("unsupported bundle version {}", bundle.version)));
+  }
+  if !config::is_valid_name(&bundle.name) {
+    return Err(Error::invalid(path, format!("invalid profile name {:?}", bundle.name)));
+  }
+
+  let mut needles: Vec<Needle> = vec![];
+  for needle in bundle.needles {
+    if needle.civ.trim().is_empty() || needles.iter().any(|n| n.civ == needle.civ) {
+      return Err(Error::invalid(path, format!("invalid or duplicate civilization {:?}", needle.civ)));
+    }
+    let png = BASE64.decode(&needle.png)
+      .map_err(|err| Error::invalid(path, format!("{}: {}", needle.civ, err)))?;
+    let data = image::load_from_memory_with_format(&png, image::ImageFormat::Png)
+      .map_err(|err| Error::invalid(path, format!("{}: {}", needle.civ, err)))?
+      .to_luma8();
+    needles.push(Needle { civ: needle.civ, data });
+  }
+
+  if needles.is_empty() {
+    return Err(Error::invalid(path, "the bundle has no villager image"));
+  }
+
+  let config = Config {
+    name: bundle.name,
+    window_title: bundle.window_title,
+    needles,
+    search: bundle.search,
+    reference_height: bundle.reference_height,
+    scales: bundle.scales,
+    alert: bundle.alert,
+    settings: bundle.settings,
+  };
+  config.validate().map_err(|err| Error::invalid(path, err))?;
+
+  for stage in &config.alert.stages {
+    if let Some(sound) = stage.sound.as_ref().filter(|sound| !sound.exists()) {
+      // The sounds are referenced, not bundled, the tone is played instead
+      eprintln!("bundle: sound {:?} of stage {:?} not found", sound, stage.name);
+    }
+  }
+
+  return Ok(config);
+}
+
+/// Reads a bundle and installs it, renamed if a profile with the same name
+/// already exists
+pub fn install(path: &Path) -> Result<Config> {
+  let mut config = read(path)?;
+
+  let profiles = config::list();
+  let name = config.name.clone();
+  let mut index = 2;
+  while profiles.contains(&config.name) {
+    config.name = format!("{} {}", name, index);
+    index += 1;
+  }
+
+  config::write(&config)?;
+
+  return Ok(config);
+}
+
+#[cfg(test)]
+mod tests {
+  use std::path::PathBuf;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use serde_json::{json, Value};
+
+  use super::*;
+
+  /// A file of its own for each call, tests run in parallel
+  fn temp_path() -> PathBuf {
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    let index = COUNT.fetch_add(1, Ordering::Relaxed);
+    return std::env::temp_dir().join(format!("aoe4-villager-enforcer-bundle-{}-{}.json", std::process::id(), index));
+  }
+
+  /// Bundle of a configured profile, as JSON
+  fn exported() -> Value {
+    let config = Config {
+      name: "coach".to_owned(),
+      needles: vec![Needle { civ: "english".to_owned(), data: image::GrayImage::new(4, 4) }],
+      reference_height: Some(1080),
+      ..Default::default()
+    };
+    let path = temp_path();
+    export(&config, &path).unwrap();
+    let value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    return value;
+  }
+
+  fn read_value(value: &Value) -> Result<Config> {
+    let path = temp_path();
+    std::fs::write(&path, value.to_string()).unwrap();
+    let result = read(&path);
+    std::fs::remove_file(&path).unwrap();
+    return result;
+  }
+
+  #[test]
+  fn reads_an_exported_profile() {
+    let config = read_value(&exported()).unwrap();
+    assert_eq!(config.name, "coach");
+    assert_eq!(config.needles.len(), 1);
+    assert_eq!(config.needles[0].civ, "english");
+    assert_eq!(config.needles[0].data.dimensions(), (4, 4));
+    assert_eq!(config.reference_height, Some(1080));
+  }
+
+  #[test]
+  fn rejects_other_files() {
+    let mut value = exported();
+    value["format"] = json!("something-else");
+    assert!(read_value(&value).is_err());
+    assert!(read_value(&json!([1, 2, 3])).is_err());
+  }
+
+  #[test]
+  fn rejects_unknown_fields_and_versions() {
+    let mut value = exported();
+    value["extra"] = json!(true);
+    assert!(read_value(&value).is_err());
+
+    let mut value = exported();
+    value["version"] = json!(VERSION + 1);
+    assert!(read_value(&value).is_err());
+  }
+
+  #[test]
+  fn rejects_invalid_names_and_needles() {
+    let mut value = exported();
+    value["name"] = json!("../escape");
+    assert!(read_value(&value).is_err());
+
+    let mut value = exported();
+    let needle = value["needles"][0].clone();
+    value["needles"] = json!([needle.clone(), needle]);
+    assert!(read_value(&value).is_err());
+
+    let mut value = exported();
+    value["needles"][0]["png"] = json!("not base64!");
+    assert!(read_value(&value).is_err());
+
+    let mut value = exported();
+    value["needles"] = json!([]);
+    assert!(read_value(&value).is_err());
+  }
+
+  #[test]
+  fn rejects_invalid_settings() {
+    let mut value = exported();
+    value["scales"] = json!([0.0]);
+    assert!(read_value(&value).is_err());
+
+    let mut value = exported();
+    value["search"]["left"] = json!(0.9);
+    assert!(read_value(&value).is_err());
+
+    let mut value = exported();
+    value["alert"]["stages"][0]["volume"] = json!(2.0);
+    assert!(read_value(&value).is_err());
+
+    let mut value = exported();
+    value["alert"]["stages"][0]["repeat_ms"] = json!(0);
+    assert!(read_value(&value).is_err());
+  }
+
+  #[test]
+  fn migrates_version_1() {
+    let mut value = exported();
+    value["version"] = json!(1);
+    value.as_object_mut().unwrap().remove("search");
+    value["y_max"] = json!(1080);
+    let config = read_value(&value).unwrap();
+    assert!(config.search.validate().is_ok());
+  }
+}
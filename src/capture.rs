@@ -1,5 +1,6 @@
 use xcap::Window;
 use egui::TextureHandle;
+use aoe4_villager_enforcer::error::Result;
 
 #[derive(Clone)]
 pub struct Capture {
@@ -8,12 +9,14 @@ pub struct Capture {
   pub texture: Option<TextureHandle>,
 }
 
-pub fn take_all() -> Vec<Capture> {
-  let windows = Window::all().unwrap();
+/// Captures every window. Windows that can't be captured, e.g. minimized
+/// ones, are left out.
+pub fn take_all() -> Result<Vec<Capture>> {
+  let windows = Window::all()?;
   let mut results = vec![];
 
   for window in windows {
-    println!(
+    eprintln!(
       "Window: {:?} {:?} {:?} {:?}",
       window.id(),
       window.app_name(),
@@ -21,7 +24,13 @@ pub fn take_all() -> Vec<Capture> {
       (window.x(), window.y(), window.width(), window.height()),
     );
 
-    let image = window.capture_image().unwrap();
+    let image = match window.capture_image() {
+      Ok(image) => image,
+      Err(err) => {
+        eprintln!("Window {:?}: {}", window.title(), err);
+        continue;
+      }
+    };
 
     let result = Capture {
       window,
@@ -32,36 +41,5 @@ pub fn take_all() -> Vec<Capture> {
     results.push(result);
   }
 
-  return results;
-}
-
-pub fn take_one(window_id: u32) -> Option<Capture> {
-  println!("take_one: start");
-  let windows = Window::all().unwrap();
-  println!("take_one: windows");
-
-  for window in windows {
-    println!(
-      "Window: {:?} {:?} {:?} {:?}",
-      window.id(),
-      window.app_name(),
-      window.title(),
-      (window.x(), window.y(), window.width(), window.height()),
-    );
-
-    if window.id() != window_id {
-      continue;
-    }
-
-    let image = window.capture_image().unwrap();
-
-    return Some(Capture {
-      window,
-      data: image,
-      texture: None,
-    });
-  }
-
-  return None;
+  return Ok(results);
 }
-
@@ -0,0 +1,497 @@
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::{Duration, Instant};
+use parking_lot::RwLock;
+use pico_args::Arguments;
+use serde_json::json;
+
+use aoe4_villager_enforcer::detector::MultiDetector;
+use aoe4_villager_enforcer::region;
+use aoe4_villager_enforcer::replay;
+use aoe4_villager_enforcer::source::{DirectorySource, FrameSource, ImageSource, WindowSource};
+
+use crate::bundle;
+use crate::capture;
+use crate::config;
+use crate::events::WatcherEvent;
+use crate::metrics::Metrics;
+use crate::reload::Reloader;
+use crate::sound::RecordingSink;
+use crate::state::State;
+use crate::watcher::Watcher;
+use crate::TITLE;
+
+/// How often `watch --metrics` rewrites the file on a live window
+const METRICS_INTERVAL: Duration = Duration::from_secs(5);
+
+const NOT_CONFIGURED: &str = "no villager image configured, run `configure` or the app first";
+
+type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+const HELP: &str = "\
+Usage: aoe4-villager-enforcer [--profile <name>] [COMMAND]
+
+Without a command, opens the app. Without --profile, uses the profile picked last in the app.
+
+Commands:
+  detect <png>                                     Print the villager match in a screenshot, as JSON
+  configure --from-screenshot <png> --square <n> [--civ <name>]
+                                                   Use the n-th detected square (from 0) as villager
+                                                   image for a civilization
+  configure --from-screenshot <png> --needle <x,y,w,h> [--search <x,y,w,h>] [--civ <name>]
+                                                   Use a rectangle of the screenshot as villager image,
+                                                   and optionally another one as search region
+  watch [--frames <png|dir> [--record <wav>]] [--metrics <file>]
+                                                   Run the watcher without the app, on recorded
+                                                   frames the alerts can be saved to a WAV file,
+                                                   --metrics saves p50/p95 timings of each stage
+                                                   as JSON
+  windows                                          List the windows that can be captured, as JSON
+  stats <dir> [--csv] [--output <file>]            Score a directory of recorded frames
+  profiles                                         List the profiles, as JSON
+  export <file>                                    Save the profile to a file that can be shared
+  import <file> [--name <name>] [--force]          Install a profile saved with export, --force
+                                                   replaces an existing profile with the same name
+";
+
+/// Runs the command line interface on `profile`, returns the exit code
+pub fn run(mut args: Arguments, profile: Option<String>) -> i32 {
+  let profile = profile.unwrap_or_else(config::selected);
+
+  let result = match args.subcommand() {
+    Ok(Some(command)) => match command.as_str() {
+      "detect" => detect(args, &profile),
+      "configure" => configure(args, &profile),
+      "watch" => watch(args, &profile),
+      "windows" => windows(args),
+      "stats" => stats(args, &profile),
+      "profiles" => profiles(args, &profile),
+      "export" => export(args, &profile),
+      "import" => import(args),
+      "help" => { print!("{}", HELP); Ok(()) },
+      _ => Err(format!("unknown command: {}", command).into()),
+    },
+    Ok(None) => { print!("{}", HELP); Ok(()) },
+    Err(err) => Err(err.into()),
+  };
+
+  match result {
+    Ok(()) => 0,
+    Err(err) => {
+      eprintln!("error: {}", err);
+      eprint!("\n{}", HELP);
+      1
+    }
+  }
+}
+
+fn finish(args: Arguments) -> Result<()> {
+  let remaining = args.finish();
+  if !remaining.is_empty() {
+    return Err(format!("unexpected arguments: {:?}", remaining).into());
+  }
+  return Ok(());
+}
+
+fn load_configured(profile: &str) -> Result<config::Config> {
+  let config = config::load(profile)?;
+  if !config.is_configured() {
+    return Err(format!("profile {:?}: {}", profile, NOT_CONFIGURED).into());
+  }
+  return Ok(config);
+}
+
+fn detect(mut args: Arguments, profile: &str) -> Result<()> {
+  let path: PathBuf = args.free_from_str()?;
+  finish(args)?;
+
+  let config = load_configured(profile)?;
+  let frame = image::open(&path)?.to_rgba8();
+
+  let mut detector = MultiDetector::new(config.profiles());
+  let detection = detector.detect(&frame);
+
+  let mut output = serde_json::to_value(&detection)?;
+  output["civ"] = json!(detector.locked());
+  println!("{}", output);
+
+  return Ok(());
+}
+
+fn configure(mut args: Arguments, profile: &str) -> Result<()> {
+  let path: PathBuf = args.value_from_str("--from-screenshot")?;
+  let index: Option<usize> = args.opt_value_from_str("--square")?;
+  let needle: Option<region::Rect> = args.opt_value_from_fn("--needle", parse_rect)?;
+  let search: Option<region::Rect> = args.opt_value_from_fn("--search", parse_rect)?;
+  let civ: String = args.opt_value_from_str("--civ")?.unwrap_or_else(|| config::DEFAULT_CIV.to_owned());
+  finish(args)?;
+
+  let mut config = config::load(profile)?;
+  let source_image = image::open(&path)?.to_rgba8();
+
+  let index = match (index, needle) {
+    (Some(index), None) => index,
+    (None, Some(needle)) => return configure_rect(config, &source_image, &civ, needle, search),
+    _ => return Err("either --square or --needle is required".into()),
+  };
+  if search.is_some() {
+    return Err("--search requires --needle".into());
+  }
+
+  let settings = &config.settings.region;
+  let squares = region::find_squares(&source_image, settings).squares;
+
+  for (i, square) in squares.iter().enumerate() {
+    eprintln!("square {}: ({}, {})", i, square.points[0].x(), square.points[0].y());
+  }
+
+  let square = squares.get(index).ok_or_else(|| {
+    format!("square {} not found, {} squares detected", index, squares.len())
+  })?;
+
+  let square_image = region::square_image(&source_image, square, settings).ok_or_else(|| {
+    format!("square {} is too small for square_offset {}", index, settings.square_offset)
+  })?;
+  let image = region::villager_needle(&square_image);
+  let (width, height) = source_image.dimensions();
+  let search = region::search_around(&region::square_rect(square), width, height, settings);
+
+  config.set_needle(&civ, image);
+  config.set_search_region(&search, width, height);
+  config::write(&config)?;
+
+  eprintln!("configured {} in profile {:?} with square {}, search = {:?}", civ, profile, index, config.search);
+
+  return Ok(());
+}
+
+/// Configures a villager image selected by hand, see `configure`
+fn configure_rect(
+  mut config: config::Config,
+  source_image: &image::RgbaImage,
+  civ: &str,
+  needle: region::Rect,
+  search: Option<region::Rect>,
+) -> Result<()> {
+  let (width, height) = source_image.dimensions();
+  if needle.right() > width || needle.bottom() > height {
+    return Err("--needle is outside the screenshot".into());
+  }
+
+  config.set_needle(civ, region::rect_needle(source_image, &needle));
+  match search {
+    Some(search) if search.right() > width || search.bottom() > height => {
+      return Err("--search is outside the screenshot".into());
+    }
+    Some(search) if !search.contains(&needle) => {
+      return Err("--search must contain --needle".into());
+    }
+    Some(search) => config.set_search_region(&search, width, height),
+    None => {
+      let search = region::search_around(&needle, width, height, &config.settings.region);
+      config.set_search_region(&search, width, height);
+    }
+  }
+  config::write(&config)?;
+
+  eprintln!("configured {} in profile {:?}, search = {:?}", civ, config.name, config.search);
+
+  return Ok(());
+}
+
+/// Parses `x,y,width,height`
+fn parse_rect(value: &str) -> std::result::Result<region::Rect, String> {
+  let values = value
+    .split(',')
+    .map(|v| v.trim().parse::<u32>())
+    .collect::<std::result::Result<Vec<_>, _>>()
+    .map_err(|err| format!("{:?}: {}", value, err))?;
+
+  let [x, y, width, height] = values[..] else {
+    return Err(format!("{:?}: expected x,y,width,height", value));
+  };
+  if width == 0 || height == 0 {
+    return Err(format!("{:?}: width and height must be positive", value));
+  }
+  if x.checked_add(width).is_none() || y.checked_add(height).is_none() {
+    return Err(format!("{:?}: the rectangle is out of range", value));
+  }
+
+  return Ok(region::Rect { x, y, width, height });
+}
+
+fn watch(mut args: Arguments, profile: &str) -> Result<()> {
+  let frames: Option<PathBuf> = args.opt_value_from_str("--frames")?;
+  let record: Option<PathBuf> = args.opt_value_from_str("--record")?;
+  let metrics: Option<PathBuf> = args.opt_value_from_str("--metrics")?;
+  finish(args)?;
+
+  if record.is_some() && frames.is_none() {
+    return Err("--record requires --frames".into());
+  }
+
+  let config = load_configured(profile)?;
+
+  let state = State::new(config);
+
+  match frames {
+    Some(path) => watch_frames(state, &path, record.as_deref(), metrics.as_deref()),
+    None => watch_window(state, metrics.as_deref()),
+  }
+}
+
+/// Runs the watcher on recorded screenshots and prints the result for each frame
+fn watch_frames(state: State, path: &Path, record: Option<&Path>, metrics_path: Option<&Path>) -> Result<()> {
+  let source: Box<dyn FrameSource> = if path.is_dir() {
+    Box::new(DirectorySource::new(path)?)
+  } else {
+    Box::new(ImageSource::open(path)?)
+  };
+
+  let mut watcher = Watcher::headless(Arc::new(RwLock::new(state)), source);
+  watcher.start();
+
+  let recording = Arc::new(RecordingSink::new());
+  if record.is_some() {
+    watcher.set_audio(recording.clone());
+  }
+
+  let mut metrics = Metrics::default();
+  while watcher.has_source() {
+    let sample = match watcher.check() {
+      Ok(Some(sample)) => sample,
+      Ok(None) => continue,
+      Err(err) => {
+        eprintln!("{}", err);
+        continue;
+      }
+    };
+    metrics.record(&sample.timings);
+
+    let civ = sample.civ.map(|civ| format!(" [{}]", civ)).unwrap_or_default();
+    let alert = sample.alert.map(|name| format!(" ALERT {}", name)).unwrap_or_default();
+    println!(
+      "{:?}: {} {:?}{}{}",
+      sample.timestamp,
+      sample.detection.status,
+      sample.detection.position,
+      civ,
+      alert,
+    );
+  }
+
+  if let Some(path) = record {
+    recording.write_wav(path)?;
+    eprintln!("{} audio events written to {:?}", recording.events().len(), path);
+  }
+
+  // Frames are checked one after the other, none is dropped
+  if let Some(path) = metrics_path {
+    write_metrics(path, &metrics, 0)?;
+    eprintln!("metrics of {} frames written to {:?}", metrics.frames(), path);
+  }
+
+  return Ok(());
+}
+
+/// Runs the watcher on the live game window until it is closed
+fn watch_window(mut state: State, metrics_path: Option<&Path>) -> Result<()> {
+  let title = state.config.window_title.to_owned();
+  let capture = capture::take_all()?
+    .into_iter()
+    .find(|c| c.window.title() == title)
+    .ok_or_else(|| format!("window {:?} not found", title))?;
+
+  let window_id = capture.window.id();
+  state.window_capture = Some(capture);
+
+  let state = Arc::new(RwLock::new(state));
+  Reloader::spawn(state.clone());
+  let watcher = Watcher::new(state.clone());
+  let events = watcher.subscribe();
+  watcher.set_source(WindowSource::new(window_id));
+  watcher.start();
+
+  let mut metrics = Metrics::default();
+  let mut dropped_frames = 0;
+  let mut last_metrics = Instant::now();
+  let mut last_reload_error = None;
+  loop {
+    let reload_error = state.read().reload_error.clone();
+    if let Some(err) = reload_error.as_ref().filter(|_| last_reload_error != reload_error) {
+      eprintln!("could not reload the profile, keeping the previous one: {}", err);
+    }
+    last_reload_error = reload_error;
+
+    match events.recv_timeout(Duration::from_millis(250)) {
+      Ok(WatcherEvent::DetectionChanged { detection, civ }) => {
+        let civ = civ.map(|civ| format!(" [{}]", civ)).unwrap_or_default();
+        println!("{}{}", detection.status, civ);
+      }
+      Ok(WatcherEvent::AlertFired { stage }) => println!("ALERT {}", stage),
+      Ok(WatcherEvent::FrameAnalyzed { timings, dropped }) => {
+        metrics.record(&timings);
+        dropped_frames = dropped;
+      }
+      Ok(WatcherEvent::Error(err)) => eprintln!("{}", err),
+      Ok(WatcherEvent::WindowLost) | Err(RecvTimeoutError::Disconnected) => break,
+      Ok(_) | Err(RecvTimeoutError::Timeout) => {}
+    }
+
+    if let Some(path) = metrics_path.filter(|_| last_metrics.elapsed() >= METRICS_INTERVAL) {
+      if let Err(err) = write_metrics(path, &metrics, dropped_frames) {
+        eprintln!("could not save the metrics: {}", err);
+      }
+      last_metrics = Instant::now();
+    }
+  }
+
+  watcher.shutdown();
+  eprintln!("window {:?} lost", title);
+
+  if let Some(path) = metrics_path {
+    write_metrics(path, &metrics, dropped_frames)?;
+    eprintln!("metrics of {} frames written to {:?}", metrics.frames(), path);
+  }
+
+  return Ok(());
+}
+
+/// Saves the p50/p95 of each stage to `path`, as JSON
+fn write_metrics(path: &Path, metrics: &Metrics, dropped_frames: u64) -> Result<()> {
+  let summary = metrics.summary(dropped_frames);
+  std::fs::write(path, serde_json::to_string_pretty(&summary)? + "\n")?;
+  return Ok(());
+}
+
+fn windows(args: Arguments) -> Result<()> {
+  finish(args)?;
+
+  let windows = capture::take_all()?
+    .iter()
+    .filter(|c| c.window.title() != TITLE)
+    .map(|c| json!({
+      "id": c.window.id(),
+      "app_name": c.window.app_name(),
+      "title": c.window.title(),
+      "width": c.data.width(),
+      "height": c.data.height(),
+    }))
+    .collect::<Vec<_>>();
+
+  println!("{}", serde_json::to_string_pretty(&windows)?);
+
+  return Ok(());
+}
+
+fn stats(mut args: Arguments, profile: &str) -> Result<()> {
+  let is_csv = args.contains("--csv");
+  let output: Option<PathBuf> = args.opt_value_from_str("--output")?;
+  let directory: PathBuf = args.free_from_str()?;
+  finish(args)?;
+
+  let config = load_configured(profile)?;
+  let timeline = replay::replay(&directory, config.profiles())?;
+
+  for err in &timeline.skipped {
+    eprintln!("skipped {}", err);
+  }
+
+  let content = if is_csv { timeline.to_csv() } else { timeline.to_json() + "\n" };
+  match output {
+    Some(path) => std::fs::write(path, content)?,
+    None => print!("{}", content),
+  }
+
+  eprintln!(
+    "{} frames, {:.1}s total, TC idle for {:.1}s",
+    timeline.frames,
+    timeline.duration_ms as f64 / 1000.0,
+    timeline.idle_ms as f64 / 1000.0,
+  );
+
+  return Ok(());
+}
+
+fn profiles(args: Arguments, profile: &str) -> Result<()> {
+  finish(args)?;
+
+  let profiles = config::list()
+    .into_iter()
+    .map(|name| match config::load(&name) {
+      Ok(config) => json!({
+        "name": name,
+        "selected": name == profile,
+        "civs": config.needles.iter().map(|n| n.civ.as_str()).collect::<Vec<_>>(),
+      }),
+      Err(err) => json!({
+        "name": name,
+        "selected": name == profile,
+        "error": err.to_string(),
+      }),
+    })
+    .collect::<Vec<_>>();
+
+  println!("{}", serde_json::to_string_pretty(&profiles)?);
+
+  return Ok(());
+}
+
+fn export(mut args: Arguments, profile: &str) -> Result<()> {
+  let path: PathBuf = args.free_from_str()?;
+  finish(args)?;
+
+  let config = load_configured(profile)?;
+  bundle::export(&config, &path)?;
+
+  eprintln!("profile {:?} exported to {:?}", profile, path);
+
+  return Ok(());
+}
+
+fn import(mut args: Arguments) -> Result<()> {
+  let is_forced = args.contains("--force");
+  let name: Option<String> = args.opt_value_from_str("--name")?;
+  let path: PathBuf = args.free_from_str()?;
+  finish(args)?;
+
+  let mut config = bundle::read(&path)?;
+  if let Some(name) = name {
+    if !config::is_valid_name(&name) {
+      return Err(format!("invalid profile name: {:?}", name).into());
+    }
+    config.name = name;
+  }
+
+  if !is_forced && config::list().contains(&config.name) {
+    return Err(format!("profile {:?} already exists, use --name or --force", config.name).into());
+  }
+
+  config::write(&config)?;
+
+  let civs = config.needles.iter().map(|n| n.civ.as_str()).collect::<Vec<_>>();
+  eprintln!("profile {:?} imported, civilizations: {}", config.name, civs.join(", "));
+
+  return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parses_rects() {
+    assert_eq!(parse_rect("1, 2,3,4"), Ok(region::Rect { x: 1, y: 2, width: 3, height: 4 }));
+    assert!(parse_rect("1,2,3").is_err());
+    assert!(parse_rect("1,2,0,4").is_err());
+    assert!(parse_rect("1,2,-3,4").is_err());
+  }
+
+  #[test]
+  fn rejects_rects_out_of_range() {
+    assert!(parse_rect(&format!("1,0,{},1", u32::MAX)).is_err());
+    assert!(parse_rect(&format!("0,{},1,1", u32::MAX)).is_err());
+  }
+}
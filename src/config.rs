@@ -1,75 +1,691 @@
 use std::fs;
-use std::path::Path;
+use std::io;
+use std::path::PathBuf;
+use std::time::SystemTime;
 use serde::{Serialize, Deserialize};
 use directories::ProjectDirs;
+use aoe4_villager_enforcer::detector::{DetectionSettings, MultiDetector, Profile, SearchRegion};
+use aoe4_villager_enforcer::error::{Error, Result};
+use aoe4_villager_enforcer::region::{Rect, RegionSettings};
 
 const DEFAULT_WINDOW_TITLE: &str = "Age of Empires IV ";
+/// Name of the profile stored in `config.json`, the others are stored in
+/// `profiles/<name>.json`
+pub const DEFAULT_PROFILE: &str = "default";
+/// Version of the on-disk format, bumped when its layout changes
+const VERSION: u32 = 3;
+/// Remembers the profile picked in the app
+const SELECTED_PROFILE_FILE: &str = "selected-profile";
+/// Civilization name of needles configured without one
+pub const DEFAULT_CIV: &str = "default";
 
 #[derive(Debug)]
 pub struct Config {
+  /// Name of the profile, it isn't stored in the file
+  pub name: String,
   pub window_title: String,
-  pub data: Option<image::GrayImage>,
-  pub y_max: u32,
+  /// Villager images, one per civilization
+  pub needles: Vec<Needle>,
+  pub search: SearchRegion,
+  /// Height of the window when the villager image was selected
+  pub reference_height: Option<u32>,
+  /// Needle scales to try, relative to the reference resolution
+  pub scales: Vec<f32>,
+  pub alert: AlertSettings,
+  pub settings: Settings,
+}
+
+/// Tunables of the watcher, detection and villager selection. The defaults
+/// work for most setups.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct Settings {
+  pub watcher: WatcherSettings,
+  pub detection: DetectionSettings,
+  pub region: RegionSettings,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct WatcherSettings {
+  /// Time between two captures of the window, in milliseconds, e.g. 250 for
+  /// 4 per second. The analysis of a frame runs while the next one is
+  /// captured, frames it can't keep up with are skipped.
+  pub interval_ms: u64,
+}
+
+impl Settings {
+  /// Rejects values the watcher and detector can't work with
+  pub fn validate(&self) -> Result<(), String> {
+    let detection = &self.detection;
+    let region = &self.region;
+
+    if self.watcher.interval_ms == 0 {
+      return Err("settings.watcher.interval_ms must be positive".to_owned());
+    }
+    if !(0.0..=1.0).contains(&detection.threshold) {
+      return Err("settings.detection.threshold must be between 0 and 1".to_owned());
+    }
+    if region.contrast_low >= region.contrast_high {
+      return Err("settings.region.contrast_low must be below contrast_high".to_owned());
+    }
+    if region.square_offset > 50 {
+      return Err("settings.region.square_offset must be at most 50".to_owned());
+    }
+    if region.search_margin > 1000 {
+      return Err("settings.region.search_margin must be at most 1000".to_owned());
+    }
+    if region.search_slots > 20 {
+      return Err("settings.region.search_slots must be at most 20".to_owned());
+    }
+
+    return Ok(());
+  }
+}
+
+impl Default for WatcherSettings {
+  fn default() -> WatcherSettings {
+    WatcherSettings {
+      interval_ms: 250,
+    }
+  }
+}
+
+/// When to start alerting once the villager disappears from the queue, and
+/// how the alert escalates the longer the TC stays idle.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct AlertSettings {
+  /// Consecutive checks without a villager before alerting
+  pub misses: u32,
+  /// Time without a villager before alerting, in milliseconds
+  pub grace_ms: u64,
+  /// The stage with the longest `delay_ms` reached is played, whatever
+  /// their order
+  pub stages: Vec<AlertStage>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AlertStage {
+  pub name: String,
+  /// Time since the alert started before reaching this stage, in milliseconds
+  pub delay_ms: u64,
+  /// Time between two tones, in milliseconds. It can't be shorter than the
+  /// watcher's capture interval.
+  pub repeat_ms: u64,
+  /// Tone frequency, in Hz
+  pub note: f32,
+  pub duration_ms: u64,
+  /// From 0.0 to 1.0
+  pub volume: f32,
+  /// WAV file played instead of the tone, it isn't restarted while it's
+  /// still playing
+  #[serde(default)]
+  pub sound: Option<PathBuf>,
+}
+
+impl Default for AlertSettings {
+  fn default() -> AlertSettings {
+    AlertSettings {
+      misses: 2,
+      grace_ms: 2000,
+      stages: vec![
+        AlertStage {
+          name: "warning".to_owned(),
+          delay_ms: 0,
+          repeat_ms: 3000,
+          note: 880.0,
+          duration_ms: 200,
+          volume: 0.3,
+          sound: None,
+        },
+        AlertStage {
+          name: "alarm".to_owned(),
+          delay_ms: 5000,
+          repeat_ms: 1000,
+          note: 12_800.0,
+          duration_ms: 500,
+          volume: 0.7,
+          sound: None,
+        },
+        AlertStage {
+          name: "critical".to_owned(),
+          delay_ms: 15000,
+          repeat_ms: 1000,
+          note: 14_000.0,
+          duration_ms: 900,
+          volume: 1.0,
+          sound: None,
+        },
+      ],
+    }
+  }
+}
+
+#[derive(Clone, Debug)]
+pub struct Needle {
+  pub civ: String,
+  pub data: image::GrayImage,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 struct ConfigOnDisk {
+  version: u32,
+  window_title: String,
+  #[serde(default)]
+  needles: Vec<NeedleOnDisk>,
+  search: SearchRegion,
+  #[serde(default)]
+  reference_height: Option<u32>,
+  #[serde(default = "default_scales")]
+  scales: Vec<f32>,
+  #[serde(default)]
+  alert: AlertSettings,
+  #[serde(default)]
+  settings: Settings,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct NeedleOnDisk {
+  civ: String,
+  /// PNG file, relative to the config file
+  image: PathBuf,
+}
+
+/// Layout from before the version field, the villager images were stored
+/// inline as (width, height, pixels)
+#[derive(Deserialize, Debug)]
+struct ConfigOnDiskV1 {
   window_title: String,
+  /// Single needle, from before the civilization library
+  #[serde(default)]
   data: Option<(u32, u32, Vec<u8>)>,
-  y_max: u32,
+  #[serde(default)]
+  needles: Vec<NeedleOnDiskV1>,
+  search: SearchRegion,
+  #[serde(default)]
+  reference_height: Option<u32>,
+  #[serde(default = "default_scales")]
+  scales: Vec<f32>,
+  #[serde(default)]
+  alert: AlertSettings,
+  #[serde(default)]
+  settings: Settings,
+}
+
+#[derive(Deserialize, Debug)]
+struct NeedleOnDiskV1 {
+  civ: String,
+  data: (u32, u32, Vec<u8>),
+}
+
+fn default_scales() -> Vec<f32> {
+  vec![1.0]
 }
 
 impl Default for Config {
   fn default() -> Config {
     Config {
+      name: DEFAULT_PROFILE.to_owned(),
       window_title: DEFAULT_WINDOW_TITLE.to_owned(),
-      data: None,
-      y_max: 0,
+      needles: vec![],
+      search: Default::default(),
+      reference_height: None,
+      scales: default_scales(),
+      alert: Default::default(),
+      settings: Default::default(),
     }
   }
 }
 
-pub fn load() -> Config {
-  let directories = ProjectDirs::from("com", "romgrk", "aoe4-vill-enforcer").unwrap();
-  let path = Path::new(directories.config_dir()).join("config.json");
+impl Config {
+  pub fn is_configured(&self) -> bool {
+    !self.needles.is_empty()
+  }
+
+  pub fn needle(&self, civ: &str) -> Option<&Needle> {
+    self.needles.iter().find(|needle| needle.civ == civ)
+  }
+
+  /// Adds the villager image of a civilization, replacing the previous one
+  pub fn set_needle(&mut self, civ: &str, data: image::GrayImage) {
+    match self.needles.iter_mut().find(|needle| needle.civ == civ) {
+      Some(needle) => needle.data = data,
+      None => self.needles.push(Needle { civ: civ.to_owned(), data }),
+    }
+  }
 
-  println!("Path: {:?}", path);
+  /// Searches `rect` of frames of `width` by `height` for the villager
+  pub fn set_search_region(&mut self, rect: &Rect, width: u32, height: u32) {
+    self.search = SearchRegion::from_rect(rect, width, height);
+    self.reference_height = Some(height);
+  }
 
-  let content = fs::read_to_string(path);
-  if content.is_err() {
-    return Default::default();
+  /// Rejects values the watcher and detector can't work with
+  pub fn validate(&self) -> Result<(), String> {
+    self.search.validate().map_err(|err| format!("search: {}", err))?;
+    if self.scales.is_empty() || self.scales.iter().any(|scale| !scale.is_finite() || *scale <= 0.0) {
+      return Err("scales must be positive".to_owned());
+    }
+    for stage in &self.alert.stages {
+      if !(0.0..=1.0).contains(&stage.volume) {
+        return Err(format!("alert stage {:?}: volume must be between 0 and 1", stage.name));
+      }
+      if stage.repeat_ms == 0 {
+        return Err(format!("alert stage {:?}: repeat_ms must be positive", stage.name));
+      }
+    }
+    return self.settings.validate();
   }
-  let content = content.unwrap();
 
-  let config = serde_json::from_str::<ConfigOnDisk>(&content);
-  if config.is_err() {
-    return Default::default();
+  /// Empty profile named `name`, keeping the window title
+  pub fn new_profile(&self, name: &str) -> Config {
+    Config {
+      name: name.to_owned(),
+      window_title: self.window_title.clone(),
+      ..Default::default()
+    }
   }
-  let config = config.unwrap();
 
-  println!("{:?}", config);
+  /// Whether `detector` uses the current profiles, so it can be kept along
+  /// with the needles it resized instead of building a new one
+  pub fn is_detected_by(&self, detector: &MultiDetector) -> bool {
+    let profiles = detector.profiles();
+    return profiles.len() == self.needles.len() && profiles.zip(&self.needles).all(|((name, profile), needle)| {
+      name == needle.civ
+        && profile.needle == needle.data
+        && profile.search == self.search
+        && profile.reference_height == self.reference_height
+        && profile.scales == self.scales
+        && profile.settings == self.settings.detection
+    });
+  }
 
-  return Config {
-    window_title: config.window_title,
-    data: config.data.map(|(w, h, data)| image::GrayImage::from_raw(w, h, data).unwrap()),
-    y_max: config.y_max,
+  /// The detection profile of each civilization
+  pub fn profiles(&self) -> Vec<(String, Profile)> {
+    self.needles
+      .iter()
+      .map(|needle| (needle.civ.clone(), Profile {
+        needle: needle.data.clone(),
+        search: self.search,
+        reference_height: self.reference_height,
+        scales: self.scales.clone(),
+        settings: self.settings.detection.clone(),
+      }))
+      .collect()
   }
 }
 
-pub fn write(config: &Config) -> std::io::Result<()> {
-  let directories = ProjectDirs::from("com", "romgrk", "aoe4-vill-enforcer").unwrap();
-  let path = Path::new(directories.config_dir()).join("config.json");
+fn directory() -> Result<PathBuf> {
+  let directories = ProjectDirs::from("com", "romgrk", "aoe4-vill-enforcer").ok_or(Error::NoConfigDirectory)?;
+  return Ok(directories.config_dir().to_owned());
+}
 
-  fs::create_dir_all(path.parent().unwrap())?;
+fn path(name: &str) -> Result<PathBuf> {
+  if name == DEFAULT_PROFILE {
+    return Ok(directory()?.join("config.json"));
+  }
+  return Ok(directory()?.join("profiles").join(format!("{}.json", name)));
+}
+
+/// Profile names are used as file names
+pub fn is_valid_name(name: &str) -> bool {
+  !name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_' || c == ' ')
+}
+
+/// Names of the stored profiles, the default one first
+pub fn list() -> Vec<String> {
+  let mut names = directory()
+    .and_then(|directory| Ok(fs::read_dir(directory.join("profiles"))?))
+    .map(|entries| {
+      entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().map(|e| e == "json").unwrap_or(false))
+        .filter_map(|path| path.file_stem().and_then(|s| s.to_str()).map(str::to_owned))
+        .filter(|name| is_valid_name(name) && name != DEFAULT_PROFILE)
+        .collect::<Vec<_>>()
+    })
+    .unwrap_or_default();
+  names.sort();
+  names.insert(0, DEFAULT_PROFILE.to_owned());
+  return names;
+}
+
+/// Profile picked last in the app
+pub fn selected() -> String {
+  directory()
+    .and_then(|directory| Ok(fs::read_to_string(directory.join(SELECTED_PROFILE_FILE))?))
+    .map(|name| name.trim().to_owned())
+    .ok()
+    .filter(|name| is_valid_name(name))
+    .unwrap_or_else(|| DEFAULT_PROFILE.to_owned())
+}
+
+pub fn select(name: &str) -> Result<()> {
+  let directory = directory()?;
+  fs::create_dir_all(&directory)?;
+  return Ok(fs::write(directory.join(SELECTED_PROFILE_FILE), name)?);
+}
+
+/// Loads the profile named `name`, or an empty one if it doesn't exist yet.
+/// Files in an older format are migrated, the original is kept next to it.
+pub fn load(name: &str) -> Result<Config> {
+  let path = path(name)?;
+
+  let content = match fs::read_to_string(&path) {
+    Ok(content) => content,
+    Err(err) if err.kind() == io::ErrorKind::NotFound => {
+      return Ok(Config { name: name.to_owned(), ..Default::default() });
+    }
+    Err(err) => return Err(Error::invalid(&path, err)),
+  };
+
+  let mut value = serde_json::from_str::<serde_json::Value>(&content).map_err(|err| Error::invalid(&path, err))?;
+  let version = match value.get("version") {
+    None => 1,
+    Some(version) => version
+      .as_u64()
+      .and_then(|version| u32::try_from(version).ok())
+      .ok_or_else(|| Error::invalid(&path, "invalid version"))?,
+  };
+
+  if version > VERSION {
+    return Err(Error::invalid(&path, format!("version {} is newer than this app, update it", version)));
+  }
+  if version < 3 {
+    migrate_search_region(&mut value);
+  }
+
+  let config = if version == 1 {
+    let config = serde_json::from_value::<ConfigOnDiskV1>(value).map_err(|err| Error::invalid(&path, err))?;
+    migrate_v1(name, config).map_err(|err| Error::invalid(&path, err))?
+  } else {
+    let config = serde_json::from_value::<ConfigOnDisk>(value).map_err(|err| Error::invalid(&path, err))?;
+
+    let directory = path.parent().unwrap();
+    let needles = config.needles
+      .into_iter()
+      .map(|needle| {
+        let image = image::open(directory.join(&needle.image))
+          .map_err(|err| Error::invalid(&directory.join(&needle.image), err))?;
+        Ok(Needle { civ: needle.civ, data: image.to_luma8() })
+      })
+      .collect::<Result<Vec<_>>>()?;
+
+    Config {
+      name: name.to_owned(),
+      window_title: config.window_title,
+      needles,
+      search: config.search,
+      reference_height: config.reference_height,
+      scales: config.scales,
+      alert: config.alert,
+      settings: config.settings,
+    }
+  };
+
+  config.validate().map_err(|err| Error::invalid(&path, err))?;
+
+  if version < VERSION {
+    let backup = path.with_extension(format!("json.v{}", version));
+    fs::copy(&path, &backup)?;
+    write(&config)?;
+    eprintln!("{:?}: migrated to version {}, previous file kept as {:?}", path, VERSION, backup);
+  }
+
+  return Ok(config);
+}
+
+/// Up to version 2, only the bottom of the searched area was stored, in
+/// pixels (`y_max`). The rest was guessed, or taken from the detection
+/// settings.
+pub fn migrate_search_region(value: &mut serde_json::Value) {
+  let Some(object) = value.as_object_mut() else {
+    return;
+  };
+
+  let mut detection = object
+    .get_mut("settings")
+    .and_then(|settings| settings.get_mut("detection"))
+    .and_then(|detection| detection.as_object_mut());
+  let mut take = |key: &str, default: f32| {
+    detection.as_mut()
+      .and_then(|detection| detection.remove(key))
+      .and_then(|value| value.as_f64())
+      .map(|value| value as f32)
+      .unwrap_or(default)
+  };
+  let defaults = SearchRegion::default();
+  let left = take("search_left", defaults.left);
+  let top = take("search_top", defaults.top);
+  let width = take("search_width", defaults.width);
+
+  // Without the reference height, y_max can't be turned into a fraction,
+  // search down to the bottom of the frame
+  let y_max = object.remove("y_max").and_then(|value| value.as_u64());
+  let reference_height = object.get("reference_height").and_then(|value| value.as_u64());
+  let bottom = match (y_max, reference_height) {
+    (Some(y_max), Some(height)) if height > 0 => (y_max as f32 / height as f32).min(1.0),
+    _ => 1.0,
+  };
+  let height = if bottom > top { bottom - top } else { 1.0 - top };
+
+  object.insert("search".to_owned(), serde_json::json!(SearchRegion { left, top, width, height }));
+}
+
+fn migrate_v1(name: &str, config: ConfigOnDiskV1) -> Result<Config, String> {
+  let image = |civ: &str, (w, h, data): (u32, u32, Vec<u8>)| {
+    image::GrayImage::from_raw(w, h, data)
+      .map(|data| Needle { civ: civ.to_owned(), data })
+      .ok_or_else(|| format!("villager image of {} doesn't match its size", civ))
+  };
+
+  let mut needles = config.needles
+    .into_iter()
+    .map(|needle| image(&needle.civ, needle.data))
+    .collect::<Result<Vec<_>, _>>()?;
+
+  if let (true, Some(data)) = (needles.is_empty(), config.data) {
+    needles.push(image(DEFAULT_CIV, data)?);
+  }
+
+  return Ok(Config {
+    name: name.to_owned(),
+    window_title: config.window_title,
+    needles,
+    search: config.search,
+    reference_height: config.reference_height,
+    scales: config.scales,
+    alert: config.alert,
+    settings: config.settings,
+  });
+}
+
+/// Writes the config to the file of its profile, and each villager image to
+/// a PNG file next to it
+pub fn write(config: &Config) -> Result<()> {
+  let path = path(&config.name)?;
+  let directory = path.parent().unwrap();
+  let stem = path.file_stem().unwrap().to_string_lossy().into_owned();
+
+  fs::create_dir_all(directory)?;
+
+  let needles = config.needles
+    .iter()
+    .enumerate()
+    .map(|(index, needle)| {
+      let image = PathBuf::from(format!("{}.villager-{}.png", stem, index));
+      needle.data.save(directory.join(&image))?;
+      Ok(NeedleOnDisk { civ: needle.civ.clone(), image })
+    })
+    .collect::<Result<Vec<_>>>()?;
 
   let config_on_disk = ConfigOnDisk {
+    version: VERSION,
     window_title: config.window_title.to_owned(),
-    data: config.data.clone().map(|image| (image.width(), image.height(), image.as_raw().to_owned())),
-    y_max: config.y_max,
+    needles,
+    search: config.search,
+    reference_height: config.reference_height,
+    scales: config.scales.clone(),
+    alert: config.alert.clone(),
+    settings: config.settings.clone(),
   };
 
-  return fs::write(
-    path,
-    serde_json::to_string(&config_on_disk).unwrap(),
-  );
+  // Write then rename, so a crash can't leave a truncated config behind
+  let temporary = path.with_extension("json.tmp");
+  fs::write(&temporary, serde_json::to_string_pretty(&config_on_disk)?)?;
+  return Ok(fs::rename(temporary, path)?);
+}
+
+/// Last modification of the profile's file or of its villager images, None
+/// if it doesn't exist
+pub fn modified(name: &str) -> Option<SystemTime> {
+  let path = path(name).ok()?;
+  let modified = fs::metadata(&path).and_then(|metadata| metadata.modified()).ok()?;
+  let prefix = format!("{}.", path.file_stem()?.to_string_lossy());
+
+  let images = fs::read_dir(path.parent()?)
+    .into_iter()
+    .flatten()
+    .filter_map(|entry| entry.ok())
+    .filter(|entry| {
+      let file_name = entry.file_name().to_string_lossy().into_owned();
+      file_name.starts_with(&prefix) && file_name.ends_with(".png")
+    })
+    .filter_map(|entry| entry.metadata().and_then(|metadata| metadata.modified()).ok());
+
+  return images.chain([modified]).max();
+}
+
+/// Moves a config that can't be loaded out of the way, so the profile can be
+/// configured again
+pub fn discard(name: &str) -> Result<PathBuf> {
+  let path = path(name)?;
+  let backup = path.with_extension("json.broken");
+  fs::rename(&path, &backup)?;
+  return Ok(backup);
+}
+
+#[cfg(test)]
+mod tests {
+  use serde_json::json;
+
+  use super::*;
+
+  /// Migrates a version 1 file as `load` does
+  fn load_v1(mut value: serde_json::Value) -> Result<Config, String> {
+    migrate_search_region(&mut value);
+    let config = serde_json::from_value::<ConfigOnDiskV1>(value).map_err(|err| err.to_string())?;
+    return migrate_v1("test", config);
+  }
+
+  #[test]
+  fn migrates_the_single_needle_of_v1() {
+    let config = load_v1(json!({
+      "window_title": "Age of Empires IV ",
+      "data": [2, 1, [10, 20]],
+      "y_max": 1080,
+      "reference_height": 1080,
+    })).unwrap();
+
+    assert_eq!(config.name, "test");
+    assert_eq!(config.needles.len(), 1);
+    assert_eq!(config.needles[0].civ, DEFAULT_CIV);
+    assert_eq!(config.needles[0].data.as_raw(), &vec![10, 20]);
+    assert_eq!(config.scales, vec![1.0]);
+  }
+
+  #[test]
+  fn migrates_the_needles_of_v1() {
+    let config = load_v1(json!({
+      "window_title": "Age of Empires IV ",
+      "data": [1, 1, [0]],
+      "needles": [
+        { "civ": "english", "data": [1, 1, [1]] },
+        { "civ": "french", "data": [1, 1, [2]] },
+      ],
+    })).unwrap();
+
+    let civs = config.needles.iter().map(|needle| needle.civ.as_str()).collect::<Vec<_>>();
+    assert_eq!(civs, vec!["english", "french"]);
+  }
+
+  #[test]
+  fn rejects_v1_needles_of_the_wrong_size() {
+    let result = load_v1(json!({
+      "window_title": "Age of Empires IV ",
+      "data": [2, 2, [0]],
+    }));
+
+    assert!(result.is_err());
+  }
+
+  #[test]
+  fn migrates_y_max_to_a_search_region() {
+    let mut value = json!({
+      "y_max": 972,
+      "reference_height": 1080,
+      "settings": { "detection": { "threshold": 0.1, "search_left": 0.1, "search_top": 0.6, "search_width": 0.3 } },
+    });
+    migrate_search_region(&mut value);
+
+    let search = serde_json::from_value::<SearchRegion>(value["search"].clone()).unwrap();
+    assert_eq!(search.left, 0.1);
+    assert_eq!(search.top, 0.6);
+    assert_eq!(search.width, 0.3);
+    assert!((search.height - 0.3).abs() < 1e-6, "{:?}", search);
+    assert!(value.get("y_max").is_none());
+    assert_eq!(value["settings"]["detection"], json!({ "threshold": 0.1 }));
+  }
+
+  #[test]
+  fn migrates_y_max_without_reference_height_to_the_bottom() {
+    let mut value = json!({ "y_max": 972 });
+    migrate_search_region(&mut value);
+
+    let search = serde_json::from_value::<SearchRegion>(value["search"].clone()).unwrap();
+    let defaults = SearchRegion::default();
+    assert_eq!(search.top, defaults.top);
+    assert_eq!(search.top + search.height, 1.0);
+    assert!(search.validate().is_ok());
+  }
+
+  #[test]
+  fn migrated_search_region_is_never_empty() {
+    // y_max above the top of the default region
+    let mut value = json!({ "y_max": 100, "reference_height": 1080 });
+    migrate_search_region(&mut value);
+
+    let search = serde_json::from_value::<SearchRegion>(value["search"].clone()).unwrap();
+    assert!(search.validate().is_ok(), "{:?}", search);
+  }
+
+  #[test]
+  fn detector_is_rebuilt_when_the_profiles_change() {
+    let mut config = Config::default();
+    config.set_needle("english", image::GrayImage::new(4, 4));
+    let detector = MultiDetector::new(config.profiles());
+    assert!(config.is_detected_by(&detector));
+
+    config.settings.detection.threshold *= 2.0;
+    assert!(!config.is_detected_by(&detector));
+
+    let detector = MultiDetector::new(config.profiles());
+    config.set_needle("french", image::GrayImage::new(4, 4));
+    assert!(!config.is_detected_by(&detector));
+  }
+
+  #[test]
+  fn rejects_invalid_alert_stages() {
+    let mut config = Config::default();
+    assert!(config.validate().is_ok());
+
+    config.alert.stages[0].volume = 1.5;
+    assert!(config.validate().is_err());
+
+    config.alert.stages[0].volume = 1.0;
+    config.alert.stages[0].repeat_ms = 0;
+    assert!(config.validate().is_err());
+  }
 }
@@ -4,20 +4,27 @@ use geo::{Line, Point};
 use geo::EuclideanDistance;
 use geo::EuclideanLength;
 
-const TOLERANCE: f64 = 10.0;
-
 pub struct Square {
   pub points: [Point; 4],
   pub contour: Contour<i32>,
 }
 
-pub fn detect_squares(width: u32, height: u32, contours: &Vec<Contour<i32>>) -> Vec<Square> {
+/// Finds the contours that are squares. Contours with less than `min_points`
+/// points are skipped, and every point must be within `tolerance` pixels of
+/// a side.
+pub fn detect_squares(
+  width: u32,
+  height: u32,
+  contours: &[Contour<i32>],
+  min_points: usize,
+  tolerance: f64,
+) -> Vec<Square> {
   let mut results = vec![];
 
-  'outer: for (_index, contour) in contours.iter().enumerate() {
+  'outer: for contour in contours.iter() {
     let points = &contour.points;
 
-    if points.len() < 150 {
+    if points.len() < min_points {
       continue;
     }
 
@@ -68,10 +75,10 @@ pub fn detect_squares(width: u32, height: u32, contours: &Vec<Contour<i32>>) ->
       let dl = left.euclidean_distance(&p);
       let dr = right.euclidean_distance(&p);
 
-      if dt > TOLERANCE
-        && db > TOLERANCE
-        && dl > TOLERANCE
-        && dr > TOLERANCE
+      if dt > tolerance
+        && db > tolerance
+        && dl > tolerance
+        && dr > tolerance
       {
         continue 'outer;
       }
@@ -0,0 +1,538 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::AddAssign;
+use std::time::{Duration, Instant};
+use image::DynamicImage;
+use image::imageops::FilterType;
+use image::GenericImageView;
+use find_subimage::{Backend, SubImageFinderState};
+use serde::{Serialize, Deserialize};
+
+use crate::region::Rect;
+
+/// What the detector needs to know to find the villager icon
+#[derive(Clone, Debug)]
+pub struct Profile {
+  /// Grayscale crop of the villager icon in the production queue
+  pub needle: image::GrayImage,
+  /// Searched area of the frames
+  pub search: SearchRegion,
+  /// Height of the frame the needle was taken from. When known, the needle
+  /// is scaled to the height of the frames.
+  pub reference_height: Option<u32>,
+  /// Scales tried on top of the resolution scale, to handle different
+  /// in-game UI scales
+  pub scales: Vec<f32>,
+  pub settings: DetectionSettings,
+}
+
+/// Rectangle of the frame, as fractions of the frame size so it works at
+/// any resolution
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct SearchRegion {
+  pub left: f32,
+  pub top: f32,
+  pub width: f32,
+  pub height: f32,
+}
+
+impl Default for SearchRegion {
+  /// Left quarter of the bottom half, where the production panel usually is
+  fn default() -> SearchRegion {
+    SearchRegion {
+      left: 0.0,
+      top: 0.5,
+      width: 0.25,
+      height: 0.5,
+    }
+  }
+}
+
+impl SearchRegion {
+  /// Region of a `rect` in a frame of `width` by `height`
+  pub fn from_rect(rect: &Rect, width: u32, height: u32) -> SearchRegion {
+    SearchRegion {
+      left: rect.x as f32 / width as f32,
+      top: rect.y as f32 / height as f32,
+      width: rect.width as f32 / width as f32,
+      height: rect.height as f32 / height as f32,
+    }
+  }
+
+  /// The region in pixels of a frame of `width` by `height`
+  pub fn rect(&self, width: u32, height: u32) -> Rect {
+    let x = (self.left * width as f32).round() as u32;
+    let y = (self.top * height as f32).round() as u32;
+    let right = ((self.left + self.width) * width as f32).round() as u32;
+    let bottom = ((self.top + self.height) * height as f32).round() as u32;
+    Rect {
+      x,
+      y,
+      width: right.saturating_sub(x),
+      height: bottom.saturating_sub(y),
+    }.clamp(width, height)
+  }
+
+  /// Rejects regions that aren't within the frame
+  pub fn validate(&self) -> Result<(), String> {
+    let values = [self.left, self.top, self.width, self.height];
+    if values.iter().any(|value| !value.is_finite()) {
+      return Err("the search region must be numbers".to_owned());
+    }
+    if self.width <= 0.0 || self.height <= 0.0 {
+      return Err("the search region is empty".to_owned());
+    }
+    // Some slack for rounding errors
+    if self.left < 0.0 || self.top < 0.0 || self.left + self.width > 1.001 || self.top + self.height > 1.001 {
+      return Err("the search region is outside the frame".to_owned());
+    }
+    return Ok(());
+  }
+}
+
+/// Tunables of the detection
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct DetectionSettings {
+  /// Largest normalized square difference accepted as a match, from 0.0
+  /// (identical) to 1.0
+  pub threshold: f32,
+  /// Mean luma of the searched area under which the frame is considered black
+  pub black_level: f32,
+  /// Luma standard deviation of the searched area under which it is
+  /// considered flat, e.g. a menu or loading screen covering the production
+  /// panel
+  pub min_contrast: f32,
+}
+
+impl Default for DetectionSettings {
+  fn default() -> DetectionSettings {
+    DetectionSettings {
+      threshold: find_subimage::NONOPENCV_DEFAULT_THRESHOLD,
+      black_level: 8.0,
+      min_contrast: 3.0,
+    }
+  }
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DetectionStatus {
+  Queued,
+  NotQueued,
+  /// The frame can't tell whether a villager is queued
+  Unknown { reason: String },
+}
+
+impl DetectionStatus {
+  fn unknown(reason: &str) -> Self {
+    DetectionStatus::Unknown { reason: reason.to_owned() }
+  }
+}
+
+impl fmt::Display for DetectionStatus {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      DetectionStatus::Queued => write!(f, "queued"),
+      DetectionStatus::NotQueued => write!(f, "not queued"),
+      DetectionStatus::Unknown { reason } => write!(f, "unknown ({})", reason),
+    }
+  }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Detection {
+  pub status: DetectionStatus,
+  /// Position of the best match, in frame coordinates
+  pub position: Option<(usize, usize)>,
+  /// Distance of the best match, smaller is a more precise match
+  pub distance: Option<f32>,
+  /// Scale of the needle for the best match
+  pub scale: Option<f32>,
+}
+
+/// Time spent in each step of the last detection, summed over the scales
+/// and profiles tried
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DetectionTimings {
+  /// Cropping the frame to the search region
+  pub crop: Duration,
+  /// Converting the crop to grayscale
+  pub luma: Duration,
+  /// Looking for the needle in the crop
+  pub matching: Duration,
+}
+
+impl AddAssign for DetectionTimings {
+  fn add_assign(&mut self, other: DetectionTimings) {
+    self.crop += other.crop;
+    self.luma += other.luma;
+    self.matching += other.matching;
+  }
+}
+
+pub struct Detector {
+  profile: Profile,
+  finder: SubImageFinderState,
+  /// Needle resized for the scales seen so far, by size
+  needles: HashMap<(u32, u32), image::GrayImage>,
+  timings: DetectionTimings,
+}
+
+impl Detector {
+  pub fn new(profile: Profile) -> Self {
+    let mut finder = SubImageFinderState::new();
+    match finder.backend_mut() {
+      Backend::RuntimeDetectedSimd { threshold, .. } | Backend::Scalar { threshold, .. } => {
+        *threshold = profile.settings.threshold;
+      }
+    }
+
+    Detector {
+      profile,
+      finder,
+      needles: HashMap::new(),
+      timings: Default::default(),
+    }
+  }
+
+  pub fn profile(&self) -> &Profile {
+    &self.profile
+  }
+
+  /// Time spent in each step of the last `detect()`
+  pub fn timings(&self) -> DetectionTimings {
+    self.timings
+  }
+
+  /// Looks for the villager needle in the production panel area of the frame,
+  /// at each of the profile's scales
+  pub fn detect(&mut self, frame: &image::RgbaImage) -> Detection {
+    let rect = match self.search_rect(frame) {
+      Ok(rect) => rect,
+      Err(err) => {
+        self.timings = Default::default();
+        return Detection::unknown(&err);
+      }
+    };
+    let haystack = Haystack::new(frame, rect);
+    let detection = self.detect_in(&haystack, frame.height());
+    self.timings.crop = haystack.crop;
+    self.timings.luma = haystack.luma;
+    return detection;
+  }
+
+  /// Area of the frame searched
+  fn search_rect(&self, frame: &image::RgbaImage) -> Result<Rect, String> {
+    self.profile.search.validate()?;
+    return Ok(self.profile.search.rect(frame.width(), frame.height()));
+  }
+
+  /// Looks for the needle in a crop of a frame of `frame_height`, only the
+  /// matching is timed
+  fn detect_in(&mut self, haystack: &Haystack, frame_height: u32) -> Detection {
+    self.timings = Default::default();
+
+    let settings = &self.profile.settings;
+    if haystack.mean < settings.black_level {
+      return Detection::unknown("frame is black");
+    }
+    if haystack.deviation < settings.min_contrast {
+      return Detection::unknown("production panel not visible");
+    }
+
+    // Scale of the frame relative to the one the profile was configured with
+    let base_scale = self.profile.reference_height
+      .map(|height| frame_height as f32 / height as f32)
+      .unwrap_or(1.0);
+
+    let mut best: Option<Detection> = None;
+    let mut unknown: Option<Detection> = None;
+
+    for scale in self.profile.scales.clone() {
+      let detection = self.detect_at_scale(haystack, base_scale * scale);
+
+      match detection.status {
+        DetectionStatus::Queued => {
+          let is_better = best.as_ref()
+            .and_then(|b| b.distance)
+            .map(|distance| detection.distance.unwrap_or(1.0) < distance)
+            .unwrap_or(true);
+          if is_better {
+            best = Some(detection);
+          }
+        }
+        DetectionStatus::NotQueued => {
+          if best.is_none() {
+            best = Some(detection);
+          }
+        }
+        DetectionStatus::Unknown { .. } => {
+          unknown = Some(detection);
+        }
+      }
+    }
+
+    return best
+      .or(unknown)
+      .unwrap_or_else(|| Detection::unknown("no scale configured"));
+  }
+
+  fn detect_at_scale(&mut self, haystack: &Haystack, scale: f32) -> Detection {
+    let needle_size = (
+      ((self.profile.needle.width() as f32 * scale).round() as u32).max(1),
+      ((self.profile.needle.height() as f32 * scale).round() as u32).max(1),
+    );
+    let needle_image = self.needles.entry(needle_size).or_insert_with(|| {
+      if needle_size == self.profile.needle.dimensions() {
+        return self.profile.needle.clone();
+      }
+      image::imageops::resize(&self.profile.needle, needle_size.0, needle_size.1, FilterType::Triangle)
+    });
+
+    let haystack_image = &haystack.image;
+    if haystack_image.width() < needle_image.width() || haystack_image.height() < needle_image.height() {
+      return Detection::unknown("search area is smaller than the villager image");
+    }
+
+    // These are (x, y, distance) where x and y are the position within the larger image
+    // and distance is the distance value, where a smaller distance means a more precise match
+    let started = Instant::now();
+    let positions =
+      self.finder.find_subimage_positions(
+        (haystack_image.as_raw(), haystack_image.width() as usize, haystack_image.height() as usize),
+        (needle_image.as_raw(), needle_image.width() as usize, needle_image.height() as usize),
+        1
+      );
+    self.timings.matching += started.elapsed();
+
+    let position =
+      positions
+        .iter()
+        .min_by(|(_, _, dist), (_, _, dist2)| dist.total_cmp(dist2));
+
+    let Rect { x, y, .. } = haystack.rect;
+    return match position {
+      Some((px, py, distance)) => Detection {
+        status: DetectionStatus::Queued,
+        position: Some((px + x as usize, py + y as usize)),
+        distance: Some(*distance),
+        scale: Some(scale),
+      },
+      None => Detection {
+        status: DetectionStatus::NotQueued,
+        position: None,
+        distance: None,
+        scale: None,
+      },
+    };
+  }
+}
+
+/// Grayscale crop of a frame to a search region, shared by the scales and
+/// profiles searching the same region
+struct Haystack {
+  image: image::GrayImage,
+  /// Where the crop is in the frame
+  rect: Rect,
+  /// Luma mean and standard deviation
+  mean: f32,
+  deviation: f32,
+  /// Time spent cropping and converting the frame
+  crop: Duration,
+  luma: Duration,
+}
+
+impl Haystack {
+  fn new(frame: &image::RgbaImage, rect: Rect) -> Haystack {
+    // Slice the haystack a bit to make it faster
+    let started = Instant::now();
+    let cropped = frame.view(rect.x, rect.y, rect.width, rect.height).to_image();
+    let cropped_at = Instant::now();
+    let image = DynamicImage::ImageRgba8(cropped).to_luma8();
+    let (mean, deviation) = luma_stats(&image);
+
+    return Haystack {
+      image,
+      rect,
+      mean,
+      deviation,
+      crop: cropped_at - started,
+      luma: cropped_at.elapsed(),
+    };
+  }
+}
+
+/// Tries several profiles, e.g. one per civilization, and locks onto the
+/// first one that matches so the next frames only use that one.
+pub struct MultiDetector {
+  detectors: Vec<(String, Detector)>,
+  locked: Option<usize>,
+  timings: DetectionTimings,
+}
+
+impl MultiDetector {
+  pub fn new(profiles: Vec<(String, Profile)>) -> Self {
+    MultiDetector {
+      detectors: profiles
+        .into_iter()
+        .map(|(name, profile)| (name, Detector::new(profile)))
+        .collect(),
+      locked: None,
+      timings: Default::default(),
+    }
+  }
+
+  /// Time spent in each step of the last `detect()`, over all the detectors
+  /// that ran
+  pub fn timings(&self) -> DetectionTimings {
+    self.timings
+  }
+
+  /// The profiles tried, with their name
+  pub fn profiles(&self) -> impl ExactSizeIterator<Item = (&str, &Profile)> {
+    self.detectors.iter().map(|(name, detector)| (name.as_str(), detector.profile()))
+  }
+
+  /// Name of the profile locked onto, if any
+  pub fn locked(&self) -> Option<&str> {
+    self.locked.map(|index| self.detectors[index].0.as_str())
+  }
+
+  /// Locks onto the profile named `name`, if it exists
+  pub fn lock(&mut self, name: &str) {
+    self.locked = self.detectors.iter().position(|(n, _)| n == name);
+  }
+
+  pub fn unlock(&mut self) {
+    self.locked = None;
+  }
+
+  /// Runs the locked detector, or all of them until one matches. Unknown
+  /// frames (menus, loading screens) release the lock, so the next game can
+  /// be played with another civilization.
+  pub fn detect(&mut self, frame: &image::RgbaImage) -> Detection {
+    self.timings = Default::default();
+
+    if let Some(index) = self.locked {
+      let detection = self.detectors[index].1.detect(frame);
+      self.timings = self.detectors[index].1.timings();
+      if matches!(detection.status, DetectionStatus::Unknown { .. }) {
+        self.locked = None;
+      }
+      return detection;
+    }
+
+    let mut best: Option<(usize, Detection)> = None;
+    let mut fallback: Option<Detection> = None;
+    // The profiles usually share the search region, it's only cropped once
+    let mut haystacks: Vec<Haystack> = vec![];
+
+    for (index, (_, detector)) in self.detectors.iter_mut().enumerate() {
+      let detection = match detector.search_rect(frame) {
+        Ok(rect) => {
+          let position = haystacks.iter().position(|haystack| haystack.rect == rect);
+          let haystack = match position {
+            Some(position) => &haystacks[position],
+            None => {
+              let haystack = Haystack::new(frame, rect);
+              self.timings.crop += haystack.crop;
+              self.timings.luma += haystack.luma;
+              haystacks.push(haystack);
+              haystacks.last().unwrap()
+            }
+          };
+          let detection = detector.detect_in(haystack, frame.height());
+          self.timings += detector.timings();
+          detection
+        }
+        Err(err) => Detection::unknown(&err),
+      };
+
+      match detection.status {
+        DetectionStatus::Queued => {
+          let is_better = best.as_ref()
+            .map(|(_, b)| detection.distance.unwrap_or(1.0) < b.distance.unwrap_or(1.0))
+            .unwrap_or(true);
+          if is_better {
+            best = Some((index, detection));
+          }
+        }
+        DetectionStatus::NotQueued => {
+          fallback = Some(detection);
+        }
+        DetectionStatus::Unknown { .. } => {
+          if fallback.is_none() {
+            fallback = Some(detection);
+          }
+        }
+      }
+    }
+
+    if let Some((index, detection)) = best {
+      self.locked = Some(index);
+      return detection;
+    }
+
+    return fallback.unwrap_or_else(|| Detection::unknown("no villager image configured"));
+  }
+}
+
+impl Detection {
+  fn unknown(reason: &str) -> Self {
+    Detection {
+      status: DetectionStatus::unknown(reason),
+      position: None,
+      distance: None,
+      scale: None,
+    }
+  }
+}
+
+/// Mean and standard deviation of the luma values
+fn luma_stats(image: &image::GrayImage) -> (f32, f32) {
+  let count = image.as_raw().len().max(1) as f64;
+  let sum = image.as_raw().iter().map(|v| *v as f64).sum::<f64>();
+  let mean = sum / count;
+  let variance = image.as_raw().iter().map(|v| (*v as f64 - mean).powi(2)).sum::<f64>() / count;
+  return (mean as f32, variance.sqrt() as f32);
+}
+
+#[cfg(test)]
+mod tests {
+  use image::{GrayImage, RgbaImage};
+
+  use super::*;
+  use crate::test_support::{frame, needle};
+
+  fn profile(needle: GrayImage) -> Profile {
+    return Profile {
+      needle,
+      search: SearchRegion { left: 0.0, top: 0.5, width: 0.5, height: 0.5 },
+      reference_height: None,
+      scales: vec![0.9, 1.0],
+      settings: DetectionSettings::default(),
+    };
+  }
+
+  #[test]
+  fn locks_onto_the_matching_profile() {
+    let mut detector = MultiDetector::new(vec![
+      ("english".to_owned(), profile(needle(3))),
+      ("french".to_owned(), profile(needle(4))),
+    ]);
+
+    let detection = detector.detect(&frame(&needle(4)));
+    assert_eq!(detection.status, DetectionStatus::Queued);
+    assert_eq!(detection.position, Some((20, 150)));
+    assert_eq!(detector.locked(), Some("french"));
+  }
+
+  #[test]
+  fn black_frames_are_unknown() {
+    let mut detector = MultiDetector::new(vec![("english".to_owned(), profile(needle(3)))]);
+    let detection = detector.detect(&RgbaImage::new(200, 200));
+    assert!(matches!(detection.status, DetectionStatus::Unknown { .. }));
+    assert_eq!(detector.locked(), None);
+  }
+}
@@ -0,0 +1,31 @@
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+/// Errors of the crate and of the app, shown to the user instead of
+/// stopping the program
+#[derive(Debug, Error)]
+pub enum Error {
+  #[error(transparent)]
+  Io(#[from] std::io::Error),
+  #[error(transparent)]
+  Image(#[from] image::ImageError),
+  #[error(transparent)]
+  Json(#[from] serde_json::Error),
+  /// The windows couldn't be listed or captured
+  #[error("capture failed: {0}")]
+  Capture(#[from] xcap::XCapError),
+  /// A file that exists but can't be used
+  #[error("{}: {message}", path.display())]
+  Invalid { path: PathBuf, message: String },
+  #[error("no home directory to store the config in")]
+  NoConfigDirectory,
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl Error {
+  pub fn invalid(path: &Path, message: impl Display) -> Error {
+    Error::Invalid { path: path.to_owned(), message: message.to_string() }
+  }
+}
@@ -0,0 +1,62 @@
+use std::sync::mpsc::{channel, Receiver, Sender};
+use parking_lot::Mutex;
+use aoe4_villager_enforcer::detector::Detection;
+
+use crate::watcher::{Timings, WatcherMode};
+
+/// What the watcher publishes, for the app, loggers or any other consumer
+#[derive(Clone, Debug)]
+pub enum WatcherEvent {
+  /// Started, stopped or paused
+  ModeChanged(WatcherMode),
+  /// A frame was captured, `Watcher::take_frame()` returns it. Frames don't
+  /// go through the queues, a subscriber that isn't drained would keep them
+  /// all in memory.
+  FrameCaptured,
+  /// The status or the civilization locked onto changed
+  DetectionChanged {
+    detection: Detection,
+    civ: Option<String>,
+  },
+  /// An alert stage was played
+  AlertFired {
+    stage: String,
+  },
+  /// The analysis of a frame is done
+  FrameAnalyzed {
+    timings: Timings,
+    /// Frames replaced by a newer one before they were analyzed, since the
+    /// watcher was started
+    dropped: u64,
+  },
+  /// The source is gone, e.g. the window was closed
+  WindowLost,
+  Error(String),
+}
+
+/// Sends each event to every subscriber, each with its own queue. Events are
+/// small and never dropped, so a subscriber misses no mode change or error
+/// even if it isn't drained for a while.
+pub struct Broadcast<T: Clone> {
+  subscribers: Mutex<Vec<Sender<T>>>,
+}
+
+impl<T: Clone> Default for Broadcast<T> {
+  fn default() -> Self {
+    Broadcast { subscribers: Mutex::new(vec![]) }
+  }
+}
+
+impl<T: Clone> Broadcast<T> {
+  /// Receives the events published from now on
+  pub fn subscribe(&self) -> Receiver<T> {
+    let (sender, receiver) = channel();
+    self.subscribers.lock().push(sender);
+    return receiver;
+  }
+
+  /// Subscribers whose receiver was dropped are forgotten
+  pub fn publish(&self, event: T) {
+    self.subscribers.lock().retain(|subscriber| subscriber.send(event.clone()).is_ok());
+  }
+}
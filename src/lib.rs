@@ -1 +1,30 @@
+#![allow(clippy::needless_return)]
+
+//! Villager queue detection, independent of the app.
+//!
+//! ```no_run
+//! use aoe4_villager_enforcer::detector::{Detector, DetectionSettings, Profile, SearchRegion};
+//!
+//! let needle = image::open("villager.png").unwrap().to_luma8();
+//! let mut detector = Detector::new(Profile {
+//!   needle,
+//!   search: SearchRegion { left: 0.0, top: 0.7, width: 0.3, height: 0.3 },
+//!   reference_height: Some(1080),
+//!   scales: vec![1.0],
+//!   settings: DetectionSettings::default(),
+//! });
+//!
+//! let frame = image::open("screenshot.png").unwrap().to_rgba8();
+//! let detection = detector.detect(&frame);
+//! println!("{:?} {:?}", detection.status, detection.position);
+//! ```
+
 pub mod contour;
+pub mod detector;
+pub mod error;
+pub mod region;
+pub mod replay;
+pub mod source;
+
+#[cfg(test)]
+mod test_support;
@@ -1,27 +1,62 @@
+#![allow(clippy::needless_return)]
+
+mod bundle;
+mod cli;
 mod config;
-mod contour;
 mod capture;
+mod metrics;
+mod pipeline;
+mod events;
+mod reload;
 mod state;
 mod sound;
 mod watcher;
+mod wav;
+
+#[cfg(test)]
+mod test_support;
 
 use std::sync::Arc;
+use std::sync::mpsc::Receiver;
 use std::time::Instant;
 use std::time::Duration;
 use parking_lot::RwLock;
 use eframe::egui;
 use egui::{Image, ColorImage, Layout, TextureHandle};
 use image::DynamicImage;
-use image::GenericImageView;
 
-use contour::detect_squares;
 use state::State;
 use state::Interface;
+use aoe4_villager_enforcer::contour;
+use aoe4_villager_enforcer::detector::{DetectionStatus, SearchRegion};
+use aoe4_villager_enforcer::region;
+use aoe4_villager_enforcer::source::WindowSource;
+use events::WatcherEvent;
+use metrics::Stage;
 use watcher::Watcher;
 
 const TITLE: &str = "AOE4 Villager Enforcer";
 
 fn main() -> eframe::Result {
+  let mut args = pico_args::Arguments::from_env();
+  let profile: Option<String> = match args.opt_value_from_str("--profile") {
+    Ok(profile) => profile,
+    Err(err) => {
+      eprintln!("error: {}", err);
+      std::process::exit(1);
+    }
+  };
+  if let Some(name) = profile.as_ref().filter(|name| !config::is_valid_name(name)) {
+    eprintln!("error: invalid profile name: {:?}", name);
+    std::process::exit(1);
+  }
+
+  let args = args.finish();
+  if !args.is_empty() {
+    let code = cli::run(pico_args::Arguments::from_vec(args), profile);
+    std::process::exit(code);
+  }
+
   let options = eframe::NativeOptions {
     viewport: egui::ViewportBuilder::default().with_inner_size([1200.0, 800.0]),
     ..Default::default()
@@ -35,7 +70,7 @@ fn main() -> eframe::Result {
 
       cc.egui_ctx.set_pixels_per_point(1.25);
 
-      Ok(Box::<EnforcerApp>::default())
+      Ok(Box::new(EnforcerApp::new(profile, cc.egui_ctx.clone())))
     }),
   )
 }
@@ -43,40 +78,110 @@ fn main() -> eframe::Result {
 struct EnforcerApp {
   state: Arc<RwLock<State>>,
   region_select_state: Option<RegionSelectState>,
-  watcher: Watcher,
+  watcher: Watcher<WindowSource>,
+  /// What the watcher published since the last update
+  events: Receiver<WatcherEvent>,
+  profiles: Vec<String>,
+  new_profile_name: String,
+  /// File the profile is exported to or imported from
+  bundle_path: String,
+  /// Outcome of the last import or export
+  bundle_message: Option<String>,
+  /// Values before opening the settings, restored by "Cancel"
+  settings_backup: Option<SettingsBackup>,
+}
+
+struct SettingsBackup {
+  settings: config::Settings,
+  alert: config::AlertSettings,
+  search: SearchRegion,
 }
 
 struct RegionSelectState {
+  /// Capture the textures were built from, the selections are cropped from
+  /// it as the watcher keeps replacing the live one
+  source_image: image::RgbaImage,
   display_texture: TextureHandle,
   region_squares: Vec<contour::Square>,
   region_images: Vec<image::RgbaImage>,
   region_textures: Vec<TextureHandle>,
+  civ: String,
+  /// Capture shown for the manual selection, not smoothed when zoomed in
+  capture_texture: TextureHandle,
+  zoom: f32,
+  target: ManualTarget,
+  needle_rect: Option<region::Rect>,
+  search_rect: Option<region::Rect>,
+  /// Where the current drag started, in capture pixels
+  drag_start: Option<(u32, u32)>,
+}
+
+/// Which rectangle dragging on the capture selects
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum ManualTarget {
+  Needle,
+  Search,
 }
 
-impl Default for EnforcerApp {
-  fn default() -> EnforcerApp {
-    let state = Arc::new(RwLock::new(State {
-      interface: Interface::WindowSelect,
-      captures: None,
-      window_capture: None,
-      last_capture: Instant::now(),
-      config: config::load(),
-      villager_texture: None,
-      is_watching: false,
-      is_queued: false,
-    }));
+impl RegionSelectState {
+  fn target_mut(&mut self) -> &mut Option<region::Rect> {
+    match self.target {
+      ManualTarget::Needle => &mut self.needle_rect,
+      ManualTarget::Search => &mut self.search_rect,
+    }
+  }
+}
+
+impl EnforcerApp {
+  /// Opens `profile`, or the one picked last time
+  fn new(profile: Option<String>, ctx: egui::Context) -> EnforcerApp {
+    let profile = profile.unwrap_or_else(config::selected);
+    let state = Arc::new(RwLock::new(State::open(&profile)));
+    reload::Reloader::spawn(state.clone());
+
+    let watcher = Watcher::new(state.clone());
+    let events = watcher.subscribe();
+
+    // Show the events as they come, the app only repaints on input otherwise
+    let repaint = watcher.subscribe();
+    std::thread::spawn(move || {
+      while repaint.recv().is_ok() {
+        ctx.request_repaint();
+      }
+    });
 
     EnforcerApp {
-      watcher: Watcher::new(state.clone()),
+      watcher,
+      events,
       state,
       region_select_state: None,
+      profiles: config::list(),
+      new_profile_name: String::new(),
+      bundle_path: String::new(),
+      bundle_message: None,
+      settings_backup: None,
     }
   }
 }
 
 impl eframe::App for EnforcerApp {
+  fn on_exit(&mut self, _gl: Option<&eframe::glow::Context>) {
+    self.watcher.shutdown();
+  }
+
   fn update(&mut self, ctx: &egui::Context, frame: &mut eframe::Frame) {
-    println!("app::update");
+    {
+      let mut state = self.state.write();
+      while let Ok(event) = self.events.try_recv() {
+        state.apply(event);
+      }
+      if let Some(frame) = self.watcher.take_frame() {
+        state.show_frame(frame);
+      }
+    }
+    if self.get(|s| s.config_error.is_some()) {
+      return self.ui_config_error(ctx, frame);
+    }
     match self.get(|s| s.interface) {
       Interface::WindowSelect => {
         self.ui_window_select(ctx, frame);
@@ -87,6 +192,9 @@ impl eframe::App for EnforcerApp {
       Interface::Main => {
         self.ui_main(ctx, frame);
       }
+      Interface::Settings => {
+        self.ui_settings(ctx, frame);
+      }
     }
   }
 }
@@ -98,30 +206,81 @@ impl EnforcerApp {
     return value;
   }
 
+  fn ui_config_error(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+    let mut state = self.state.write();
+    let name = state.config.name.clone();
+
+    egui::CentralPanel::default().show(ctx, |ui| {
+      ui.vertical(|ui| {
+        ui.heading(format!("Profile {} couldn't be loaded", name));
+        ui.label(egui::RichText::new(state.config_error.clone().unwrap_or_default()).color(egui::Color32::from_rgb(225, 10, 50)));
+        ui.label("Fix the file and retry, or start over with an empty profile. The file is kept as a backup.");
+
+        ui.horizontal(|ui| {
+          if ui.button("Retry").clicked() {
+            match config::load(&name) {
+              Ok(config) => {
+                state.config = config;
+                state.config_error = None;
+              }
+              Err(err) => state.config_error = Some(err.to_string()),
+            }
+          }
+
+          if ui.button("Start over").clicked() {
+            match config::discard(&name) {
+              Ok(_) => state.config_error = None,
+              Err(err) => state.config_error = Some(err.to_string()),
+            }
+          }
+        });
+      });
+    });
+  }
+
   fn ui_window_select(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
     let mut state = self.state.write();
 
     if state.captures.is_none() || Instant::now().duration_since(state.last_capture) > Duration::from_secs(1) {
-      state.captures = Some(capture::take_all());
+      match capture::take_all() {
+        Ok(captures) => {
+          state.captures = Some(captures);
+          state.set_capture_error(None);
+        }
+        Err(err) => {
+          state.captures.get_or_insert_with(Vec::new);
+          state.set_capture_error(Some(err.to_string()));
+        }
+      }
       state.last_capture = Instant::now();
     }
 
     let title = state.config.window_title.to_owned();
-    let capture = state.captures.as_ref().map(|captures| captures.iter().find(|c| c.window.title() == title)).unwrap();
-    if capture.is_some() {
-      state.window_capture = capture.cloned();
-      state.interface = Interface::RegionSelect;
+    let capture = state.captures.iter().flatten().find(|c| c.window.title() == title).cloned();
+    if let Some(capture) = capture {
+      self.watcher.set_source(WindowSource::new(capture.window.id()));
+      state.window_capture = Some(capture);
+      state.civ = None;
+      state.interface = if state.config.is_configured() { Interface::Main } else { Interface::RegionSelect };
+      let interface = state.interface;
       drop(state);
-      return self.ui_region_select(ctx, _frame);
+      return match interface {
+        Interface::Main => self.ui_main(ctx, _frame),
+        _ => self.ui_region_select(ctx, _frame),
+      };
     }
 
     egui::CentralPanel::default().show(ctx, |ui| {
       ui.vertical(|ui| {
         ui.heading("Select AOE4 window");
 
+        if let Some(err) = state.last_error.as_ref() {
+          ui.label(egui::RichText::new(err).color(egui::Color32::from_rgb(225, 10, 50)));
+        }
+
         egui::ScrollArea::both().show(ui, |ui| {
           ui.with_layout(Layout::left_to_right(egui::Align::TOP), |ui| {
-            state.captures.as_mut().unwrap().iter_mut().for_each(|capture| {
+            state.captures.iter_mut().flatten().for_each(|capture| {
               capture.texture.get_or_insert_with(|| {
                 ui.ctx().load_texture(
                   capture.window.title(),
@@ -131,12 +290,14 @@ impl EnforcerApp {
               });
             });
 
-            state.captures.as_ref().unwrap().clone().iter().for_each(|capture| {
+            state.captures.clone().unwrap_or_default().iter().for_each(|capture| {
               if capture.window.title() == TITLE {
                 return;
               }
 
-              let texture = capture.texture.as_ref().unwrap();
+              let Some(texture) = capture.texture.as_ref() else {
+                return;
+              };
 
               let button = egui::Button::image_and_text(
                 Image::from_texture((texture.id(), texture.size_vec2()))
@@ -145,8 +306,10 @@ impl EnforcerApp {
               );
 
               if ui.add(button).clicked() {
+                self.watcher.set_source(WindowSource::new(capture.window.id()));
                 state.window_capture = Some(capture.clone());
-                state.interface = Interface::RegionSelect;
+                state.civ = None;
+                state.interface = if state.config.is_configured() { Interface::Main } else { Interface::RegionSelect };
               }
             });
           });
@@ -156,40 +319,24 @@ impl EnforcerApp {
   }
 
   fn ui_region_select(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
-    println!("ui_region_select");
     let mut state = self.state.write();
-    println!("{:?}", state.config);
 
-    if state.config.data.is_some() {
-      state.interface = Interface::Main;
-      drop(state);
-      return self.ui_main(ctx, _frame);
-    }
-
-    let capture = state.window_capture.as_ref().unwrap();
+    let Some(capture) = state.window_capture.as_ref() else {
+      state.interface = Interface::WindowSelect;
+      return;
+    };
+    let settings = state.config.settings.region.clone();
 
     let region_state = self.region_select_state.get_or_insert_with(|| {
       let source_image = &capture.data;
 
-      let processing = source_image;
-      let processing = image::imageops::colorops::grayscale(processing);
-      let grayscale_image = processing.clone();
-      let processing = imageproc::contrast::stretch_contrast(&processing, 75, 90, 0, 255);
-
-      let processed_image = processing;
-
-      let contours = imageproc::contours::find_contours::<i32>(&processed_image);
-
-      let squares = detect_squares(
-        processed_image.width(),
-        processed_image.height(),
-        &contours
-      );
+      let region::Squares { grayscale: grayscale_image, contours, squares } = region::find_squares(source_image, &settings);
 
       let mut image = DynamicImage::ImageLuma8(grayscale_image).to_rgba8();
       // let mut image = DynamicImage::ImageLuma8(processed_image).to_rgba8();
       // let mut image = source_image.clone();
 
+      let mut region_squares = vec![];
       let mut region_images = vec![];
       let mut region_textures = vec![];
 
@@ -202,7 +349,7 @@ impl EnforcerApp {
           );
         }
       }
-      for (i, square) in squares.iter().enumerate() {
+      for (i, square) in squares.into_iter().enumerate() {
         for point in &square.contour.points {
           image.put_pixel(
             point.x as u32,
@@ -211,14 +358,12 @@ impl EnforcerApp {
           );
         }
 
-        let offset = 5;
-        let region_image = source_image.view(
-          (square.points[0].x() as u32) + offset,
-          (square.points[0].y() as u32) + offset,
-          (square.points[2].x() as u32 - square.points[0].x() as u32) - offset * 2,
-          (square.points[2].y() as u32 - square.points[0].y() as u32) - offset * 2,
-        ).to_image();
+        // Too small to trim its border
+        let Some(region_image) = region::square_image(source_image, &square, &settings) else {
+          continue;
+        };
 
+        region_squares.push(square);
         region_images.push(region_image.clone());
         region_textures.push(ctx.load_texture(
           format!("{}-square-{}", capture.window.title(), i),
@@ -233,18 +378,38 @@ impl EnforcerApp {
         Default::default(),
       );
 
+      let capture_texture = ctx.load_texture(
+        format!("{}-capture", capture.window.title()),
+        image_to_egui(source_image),
+        egui::TextureOptions::NEAREST,
+      );
+
       RegionSelectState {
+        source_image: source_image.clone(),
         display_texture,
-        region_squares: squares,
+        region_squares,
         region_images,
         region_textures,
+        civ: String::new(),
+        capture_texture,
+        zoom: 1.0,
+        target: ManualTarget::Needle,
+        needle_rect: None,
+        search_rect: None,
+        drag_start: None,
       }
     });
+    let (capture_width, capture_height) = region_state.source_image.dimensions();
 
     egui::CentralPanel::default().show(ctx, |ui| {
       ui.vertical(|ui| {
         ui.heading("Select villager image");
 
+        ui.horizontal(|ui| {
+          ui.label("Civilization:");
+          ui.add(egui::TextEdit::singleline(&mut region_state.civ).hint_text(config::DEFAULT_CIV));
+        });
+
         ui.with_layout(Layout::left_to_right(egui::Align::TOP), |ui| {
           ui.vertical(|ui| {
             ui.horizontal(|ui| {
@@ -257,25 +422,23 @@ impl EnforcerApp {
                   );
 
                   if ui.add(button).clicked() {
-
-                    // Select half the image
-                    let image = &region_state.region_images[index];
-                    let image = image.view(
-                      image.width() / 2,
-                      10,
-                      image.width() / 2,
-                      image.height() / 2,
+                    let image = region::villager_needle(&region_state.region_images[index]);
+                    let search = region::search_around(
+                      &region::square_rect(&region_state.region_squares[index]),
+                      capture_width,
+                      capture_height,
+                      &settings,
                     );
-                    let image = image.to_image();
-                    let image = DynamicImage::ImageRgba8(image).to_luma8();
-
-                    state.config.data = Some(image);
-                    state.config.y_max = region_state.region_squares[index].points[3].y() as u32 + 20;
 
-                    // XXX: show error message?
-                    let _ = config::write(&state.config);
+                    let civ = match region_state.civ.trim() {
+                      "" => config::DEFAULT_CIV,
+                      civ => civ,
+                    };
+                    state.config.set_needle(civ, image);
+                    state.config.set_search_region(&search, capture_width, capture_height);
 
-                    println!("Config: {:?}", state.config);
+                    let result = config::write(&state.config);
+                    state.saved(result);
 
                     state.interface = Interface::Main;
                   }
@@ -285,6 +448,34 @@ impl EnforcerApp {
           });
         });
 
+        // Square detection only works on some backgrounds, the icon and
+        // the search region can also be selected by hand
+        let is_manual_open = region_state.region_squares.is_empty();
+        egui::CollapsingHeader::new("Manual selection").default_open(is_manual_open).show(ui, |ui| {
+          if !ui_manual_select(ui, region_state) {
+            return;
+          }
+          let Some(needle_rect) = region_state.needle_rect else {
+            return;
+          };
+          let image = region::rect_needle(&region_state.source_image, &needle_rect);
+
+          let civ = match region_state.civ.trim() {
+            "" => config::DEFAULT_CIV,
+            civ => civ,
+          };
+          let config = &mut state.config;
+          config.set_needle(civ, image);
+          let search = region_state.search_rect
+            .unwrap_or_else(|| region::search_around(&needle_rect, capture_width, capture_height, &config.settings.region));
+          config.set_search_region(&search, capture_width, capture_height);
+
+          let result = config::write(&state.config);
+          state.saved(result);
+
+          state.interface = Interface::Main;
+        });
+
         egui::ScrollArea::both().show(ui, |ui| {
           ui.add(
             Image::from_texture((
@@ -301,7 +492,10 @@ impl EnforcerApp {
   fn ui_main(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
     let mut state = self.state.write();
 
-    let capture = state.window_capture.as_mut().unwrap();
+    let Some(capture) = state.window_capture.as_mut() else {
+      state.interface = Interface::WindowSelect;
+      return;
+    };
     let capture_texture = capture.texture.get_or_insert_with(|| {
       ctx.load_texture(
         capture.window.title(),
@@ -310,7 +504,15 @@ impl EnforcerApp {
       )
     }).clone();
 
-    let villager_image = state.config.data.as_ref().unwrap().clone();
+    // Show the villager image of the detected civ, or the first one
+    let villager_image = state.civ.as_ref()
+      .and_then(|civ| state.config.needle(civ))
+      .or(state.config.needles.first())
+      .map(|needle| needle.data.clone());
+    let Some(villager_image) = villager_image else {
+      state.interface = Interface::RegionSelect;
+      return;
+    };
     let villager_texture = state.villager_texture.get_or_insert_with(|| {
       ctx.load_texture(
         "villager-texture",
@@ -319,12 +521,91 @@ impl EnforcerApp {
       )
     }).clone();
 
+    // Bundles can be dropped on the window to import them
+    let dropped = ctx.input(|input| {
+      input.raw.dropped_files.iter().filter_map(|file| file.path.clone()).collect::<Vec<_>>()
+    });
+    let mut imports = dropped;
+
     egui::CentralPanel::default().show(ctx, |ui| {
       ui.vertical(|ui| {
+        ui.horizontal(|ui| {
+          ui.label("Profile:");
+
+          let mut selected = state.config.name.clone();
+          egui::ComboBox::from_id_source("profile")
+            .selected_text(&selected)
+            .show_ui(ui, |ui| {
+              for name in &self.profiles {
+                ui.selectable_value(&mut selected, name.clone(), name);
+              }
+            });
+          if selected != state.config.name {
+            let result = config::select(&selected);
+            state.saved(result);
+            self.region_select_state = None;
+            state.load_profile(&selected);
+          }
+
+          ui.add(egui::TextEdit::singleline(&mut self.new_profile_name).hint_text("New profile"));
+          let name = self.new_profile_name.trim().to_owned();
+          let can_create = config::is_valid_name(&name) && !self.profiles.contains(&name);
+          if ui.add_enabled(can_create, egui::Button::new("Create")).clicked() {
+            let result = config::select(&name);
+            state.saved(result);
+            self.profiles.push(name.clone());
+            self.new_profile_name.clear();
+            self.region_select_state = None;
+            let config = state.config.new_profile(&name);
+            state.switch_profile(config);
+          }
+        });
+
+        ui.horizontal(|ui| {
+          ui.label("Share:");
+          ui.add(egui::TextEdit::singleline(&mut self.bundle_path).hint_text("profile.json"));
+
+          let path = std::path::PathBuf::from(self.bundle_path.trim());
+          let has_path = !self.bundle_path.trim().is_empty();
+
+          if ui.add_enabled(has_path, egui::Button::new("Export")).clicked() {
+            self.bundle_message = Some(match bundle::export(&state.config, &path) {
+              Ok(()) => format!("Exported {} to {:?}", state.config.name, path),
+              Err(err) => format!("Export failed: {}", err),
+            });
+          }
+          if ui.add_enabled(has_path, egui::Button::new("Import")).clicked() {
+            imports.push(path);
+          }
+
+          for path in imports.drain(..) {
+            self.bundle_message = Some(match bundle::install(&path) {
+              Ok(config) => {
+                let message = format!("Imported {}", config.name);
+                let result = config::select(&config.name);
+                state.saved(result);
+                self.profiles = config::list();
+                self.region_select_state = None;
+                state.switch_profile(config);
+                message
+              }
+              Err(err) => format!("Import of {:?} failed: {}", path, err),
+            });
+          }
+
+          if let Some(message) = &self.bundle_message {
+            ui.label(message);
+          }
+        });
+
         ui.horizontal(|ui| {
           ui.label("Status: ");
-          let text = if state.is_watching { "Running" } else { "Not running" };
-          let color = if state.is_watching {
+          let text = match (state.is_watching, state.is_paused) {
+            (true, false) => "Running",
+            (true, true) => "Paused",
+            (false, _) => "Not running",
+          };
+          let color = if state.is_watching && !state.is_paused {
             egui::Color32::from_rgb(10, 225, 70)
           } else {
             ui.visuals().text_color()
@@ -334,32 +615,116 @@ impl EnforcerApp {
 
         ui.horizontal(|ui| {
           ui.label("Villager queued?:");
-          let text = if !state.is_watching {
-            "Who cares"
-          } else if state.is_queued {
-            "Yes"
-          } else {
-            "No"
-          };
-          let color = if !state.is_watching {
-            ui.visuals().text_color()
-          } else if state.is_queued {
-            egui::Color32::from_rgb(10, 225, 70)
+          let (text, color) = if !state.is_watching {
+            ("Who cares".to_owned(), ui.visuals().text_color())
           } else {
-            egui::Color32::from_rgb(225, 10, 50)
+            match &state.status {
+              DetectionStatus::Queued =>
+                ("Yes".to_owned(), egui::Color32::from_rgb(10, 225, 70)),
+              DetectionStatus::NotQueued =>
+                ("No".to_owned(), egui::Color32::from_rgb(225, 10, 50)),
+              DetectionStatus::Unknown { reason } =>
+                (format!("Unknown: {}", reason), egui::Color32::from_rgb(225, 170, 10)),
+            }
           };
           ui.label(egui::RichText::new(text).color(color))
         });
 
+        ui.horizontal(|ui| {
+          ui.label("Civilization:");
+          let text = match state.civ.as_ref() {
+            Some(civ) => civ.to_owned(),
+            None => "Not detected yet".to_owned(),
+          };
+          ui.label(text);
+          let civs = state.config.needles.iter().map(|n| n.civ.as_str()).collect::<Vec<_>>();
+          ui.label(egui::RichText::new(format!("(configured: {})", civs.join(", "))).weak());
+        });
+
+        if let Some(err) = state.reload_error.as_ref() {
+          ui.label(
+            egui::RichText::new(format!("The profile file changed but couldn't be reloaded, keeping the previous one: {}", err))
+              .color(egui::Color32::from_rgb(225, 10, 50))
+          );
+        }
+
+        if let Some(err) = state.last_error.as_ref() {
+          ui.label(egui::RichText::new(format!("Last error: {}", err)).color(egui::Color32::from_rgb(225, 10, 50)));
+        }
+
+        if state.is_watching {
+          egui::CollapsingHeader::new("Performance").show(ui, |ui| {
+            ui.label(egui::RichText::new(format!(
+              "{} frames analyzed, {} dropped",
+              state.metrics.frames(),
+              state.dropped_frames,
+            )).weak());
+            egui::Grid::new("performance").num_columns(3).striped(true).show(ui, |ui| {
+              ui.strong("Stage");
+              ui.strong("p50");
+              ui.strong("p95");
+              ui.end_row();
+
+              let milliseconds = |duration: Option<Duration>| match duration {
+                Some(duration) => format!("{:.1} ms", duration.as_secs_f64() * 1000.0),
+                None => "-".to_owned(),
+              };
+              for stage in Stage::ALL {
+                let histogram = state.metrics.histogram(stage);
+                ui.label(stage.label());
+                ui.label(milliseconds(histogram.percentile(0.5)));
+                ui.label(milliseconds(histogram.percentile(0.95)));
+                ui.end_row();
+              }
+            });
+          });
+        }
+
+        if let Some(stage) = state.alert_stage.as_ref().filter(|_| state.is_watching) {
+          ui.horizontal(|ui| {
+            ui.label("Alert:");
+            ui.label(egui::RichText::new(stage).color(egui::Color32::from_rgb(225, 10, 50)))
+          });
+        }
+
         ui.horizontal(|ui| {
           if ui.button(if state.is_watching { "Stop" } else { "Start" }).clicked() {
-            state.is_watching = !state.is_watching;
+            if state.is_watching {
+              self.watcher.stop();
+            } else {
+              self.watcher.start();
+            }
+          }
+
+          if state.is_watching && ui.button(if state.is_paused { "Resume" } else { "Pause" }).clicked() {
+            if state.is_paused {
+              self.watcher.start();
+            } else {
+              self.watcher.pause();
+            }
+          }
+
+          if ui.button("Add civilization").clicked() {
+            self.region_select_state = None;
+            state.interface = Interface::RegionSelect;
+          }
+
+          if ui.button("Settings").clicked() {
+            self.settings_backup = Some(SettingsBackup {
+              settings: state.config.settings.clone(),
+              alert: state.config.alert.clone(),
+              search: state.config.search,
+            });
+            state.interface = Interface::Settings;
           }
 
           if ui.button("Reset").clicked() {
-            state.config.data = None;
-            state.config.y_max = 0;
+            state.config.needles.clear();
+            state.config.search = Default::default();
+            state.config.reference_height = None;
             state.villager_texture = None;
+            state.civ = None;
+            self.region_select_state = None;
             state.interface = Interface::WindowSelect;
           }
         });
@@ -386,6 +751,230 @@ impl EnforcerApp {
   }
 }
 
+impl EnforcerApp {
+  /// Edits the config in place: the watcher picks the changes up on its next
+  /// check. They are written to disk when leaving the screen.
+  fn ui_settings(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+    let mut state = self.state.write();
+    let state = &mut *state;
+
+    egui::CentralPanel::default().show(ctx, |ui| {
+      egui::ScrollArea::vertical().show(ui, |ui| {
+        ui.heading(format!("Settings of {}", state.config.name));
+
+        ui.add_space(8.0);
+        ui.strong("Watcher");
+        egui::Grid::new("watcher-settings").num_columns(2).show(ui, |ui| {
+          ui.label("Capture interval");
+          ui.add(egui::Slider::new(&mut state.config.settings.watcher.interval_ms, 100..=5000).suffix(" ms"));
+          ui.end_row();
+
+          ui.label("Missed checks before alerting");
+          ui.add(egui::Slider::new(&mut state.config.alert.misses, 1..=10));
+          ui.end_row();
+
+          ui.label("Grace period");
+          ui.add(egui::Slider::new(&mut state.config.alert.grace_ms, 0..=20_000).suffix(" ms"));
+          ui.end_row();
+        });
+
+        ui.add_space(8.0);
+        ui.strong("Alerts");
+        for (index, stage) in state.config.alert.stages.iter_mut().enumerate() {
+          egui::CollapsingHeader::new(&stage.name).id_source(("stage", index)).show(ui, |ui| {
+            egui::Grid::new(("stage-settings", index)).num_columns(2).show(ui, |ui| {
+              ui.label("Starts after");
+              ui.add(egui::Slider::new(&mut stage.delay_ms, 0..=60_000).suffix(" ms"));
+              ui.end_row();
+
+              ui.label("Repeats every");
+              ui.add(egui::Slider::new(&mut stage.repeat_ms, 100..=10_000).suffix(" ms"));
+              ui.end_row();
+
+              ui.label("Pitch");
+              ui.add(egui::Slider::new(&mut stage.note, 100.0..=16_000.0).logarithmic(true).suffix(" Hz"));
+              ui.end_row();
+
+              ui.label("Duration");
+              ui.add(egui::Slider::new(&mut stage.duration_ms, 50..=3000).suffix(" ms"));
+              ui.end_row();
+
+              ui.label("Volume");
+              ui.add(egui::Slider::new(&mut stage.volume, 0.0..=1.0));
+              ui.end_row();
+            });
+
+            if ui.button("Test").clicked() {
+              self.watcher.preview(stage);
+            }
+          });
+        }
+
+        ui.add_space(8.0);
+        ui.strong("Detection");
+        let detection = &mut state.config.settings.detection;
+        egui::Grid::new("detection-settings").num_columns(2).show(ui, |ui| {
+          ui.label("Matching threshold");
+          ui.add(egui::Slider::new(&mut detection.threshold, 0.01..=0.5));
+          ui.end_row();
+
+          // Fractions of the window, kept within it
+          let search = &mut state.config.search;
+          ui.label("Search area left");
+          ui.add(egui::Slider::new(&mut search.left, 0.0..=1.0 - search.width));
+          ui.end_row();
+
+          ui.label("Search area top");
+          ui.add(egui::Slider::new(&mut search.top, 0.0..=1.0 - search.height));
+          ui.end_row();
+
+          ui.label("Search area width");
+          ui.add(egui::Slider::new(&mut search.width, 0.01..=1.0 - search.left));
+          ui.end_row();
+
+          ui.label("Search area height");
+          ui.add(egui::Slider::new(&mut search.height, 0.01..=1.0 - search.top));
+          ui.end_row();
+
+          ui.label("Black level");
+          ui.add(egui::Slider::new(&mut detection.black_level, 0.0..=64.0));
+          ui.end_row();
+
+          ui.label("Minimum contrast");
+          ui.add(egui::Slider::new(&mut detection.min_contrast, 0.0..=32.0));
+          ui.end_row();
+        });
+
+        ui.add_space(8.0);
+        ui.horizontal(|ui| {
+          // Stay on the screen if the profile couldn't be written
+          if ui.button("Save").clicked() && state.saved(config::write(&state.config)) {
+            self.settings_backup = None;
+            state.interface = Interface::Main;
+          }
+
+          if ui.button("Cancel").clicked() {
+            if let Some(backup) = self.settings_backup.take() {
+              state.config.settings = backup.settings;
+              state.config.alert = backup.alert;
+              state.config.search = backup.search;
+            }
+            state.interface = Interface::Main;
+          }
+
+          if ui.button("Restore defaults").clicked() {
+            state.config.settings = Default::default();
+            state.config.alert = Default::default();
+          }
+        });
+
+        if let Some(err) = state.last_error.as_ref().filter(|_| state.is_save_error) {
+          ui.label(egui::RichText::new(err).color(egui::Color32::from_rgb(225, 10, 50)));
+        }
+      });
+    });
+  }
+}
+
+/// Rectangles dragged on the capture, returns true when the selection should
+/// be used
+fn ui_manual_select(ui: &mut egui::Ui, region_state: &mut RegionSelectState) -> bool {
+  let texture = region_state.capture_texture.clone();
+  let (width, height) = region_state.source_image.dimensions();
+
+  ui.horizontal(|ui| {
+    ui.label("Drag on the capture to select the");
+    ui.selectable_value(&mut region_state.target, ManualTarget::Needle, "villager image");
+    ui.selectable_value(&mut region_state.target, ManualTarget::Search, "search region");
+    ui.separator();
+    ui.label("Zoom");
+    ui.add(egui::Slider::new(&mut region_state.zoom, 0.25..=8.0).logarithmic(true));
+  });
+
+  // Pixel-precise adjustment of the dragged rectangle
+  if let Some(rect) = region_state.target_mut().as_mut() {
+    ui.horizontal(|ui| {
+      ui.label("x");
+      ui.add(egui::DragValue::new(&mut rect.x).range(0..=width - 1));
+      ui.label("y");
+      ui.add(egui::DragValue::new(&mut rect.y).range(0..=height - 1));
+      ui.label("width");
+      ui.add(egui::DragValue::new(&mut rect.width).range(1..=width));
+      ui.label("height");
+      ui.add(egui::DragValue::new(&mut rect.height).range(1..=height));
+    });
+    *rect = rect.clamp(width, height);
+  }
+
+  let zoom = region_state.zoom;
+  egui::ScrollArea::both().id_source("manual-select").max_height(500.0).show(ui, |ui| {
+    let size = egui::vec2(width as f32, height as f32) * zoom;
+    let (response, painter) = ui.allocate_painter(size, egui::Sense::drag());
+    let origin = response.rect.min;
+    let uv = egui::Rect::from_min_max(egui::pos2(0.0, 0.0), egui::pos2(1.0, 1.0));
+    painter.image(texture.id(), response.rect, uv, egui::Color32::WHITE);
+
+    let to_capture = |pos: egui::Pos2| {
+      let pos = (pos - origin) / zoom;
+      (pos.x.clamp(0.0, width as f32) as u32, pos.y.clamp(0.0, height as f32) as u32)
+    };
+    if let Some(pos) = response.interact_pointer_pos() {
+      if response.drag_started() {
+        region_state.drag_start = Some(to_capture(pos));
+      }
+      if let Some(start) = region_state.drag_start {
+        let rect = region::Rect::from_corners(start, to_capture(pos));
+        if rect.width > 0 && rect.height > 0 {
+          *region_state.target_mut() = Some(rect);
+        }
+      }
+    }
+    if response.drag_stopped() {
+      region_state.drag_start = None;
+    }
+
+    let to_screen = |rect: &region::Rect| egui::Rect::from_min_size(
+      origin + egui::vec2(rect.x as f32, rect.y as f32) * zoom,
+      egui::vec2(rect.width as f32, rect.height as f32) * zoom,
+    );
+    if let Some(rect) = region_state.search_rect.as_ref() {
+      painter.rect_stroke(to_screen(rect), 0.0, egui::Stroke::new(2.0, egui::Color32::from_rgb(10, 225, 70)));
+    }
+    if let Some(rect) = region_state.needle_rect.as_ref() {
+      painter.rect_stroke(to_screen(rect), 0.0, egui::Stroke::new(2.0, egui::Color32::from_rgb(225, 10, 50)));
+    }
+  });
+
+  let mut is_used = false;
+  ui.horizontal(|ui| {
+    if let Some(rect) = region_state.needle_rect.as_ref() {
+      let uv = egui::Rect::from_min_size(
+        egui::pos2(rect.x as f32 / width as f32, rect.y as f32 / height as f32),
+        egui::vec2(rect.width as f32 / width as f32, rect.height as f32 / height as f32),
+      );
+      ui.add(
+        Image::from_texture((texture.id(), egui::vec2(rect.width as f32, rect.height as f32)))
+          .uv(uv)
+          .fit_to_exact_size(egui::vec2(rect.width as f32, rect.height as f32) * 4.0)
+          .max_height(80.0)
+      );
+    }
+
+    let is_contained = match (region_state.needle_rect.as_ref(), region_state.search_rect.as_ref()) {
+      (Some(needle), Some(search)) => search.contains(needle),
+      _ => true,
+    };
+    if !is_contained {
+      ui.label(egui::RichText::new("The villager image must be inside the search region").color(egui::Color32::from_rgb(225, 170, 10)));
+    }
+
+    let can_use = region_state.needle_rect.is_some() && is_contained;
+    is_used = ui.add_enabled(can_use, egui::Button::new("Use selection")).clicked();
+  });
+
+  return is_used;
+}
+
 fn image_to_egui(image: &image::RgbaImage) -> egui::ColorImage {
   ColorImage::from_rgba_unmultiplied(
     [image.width() as usize, image.height() as usize],
@@ -0,0 +1,148 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+use serde::Serialize;
+
+use crate::watcher::Timings;
+
+/// Frames the percentiles are computed on, about 2 minutes at 4 frames per
+/// second
+const WINDOW: usize = 512;
+
+/// Steps of the watcher pipeline, in order
+#[derive(Serialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+  Capture,
+  Queued,
+  Crop,
+  Luma,
+  Matching,
+  Detection,
+  Alert,
+  Total,
+}
+
+impl Stage {
+  pub const ALL: [Stage; 8] = [
+    Stage::Capture,
+    Stage::Queued,
+    Stage::Crop,
+    Stage::Luma,
+    Stage::Matching,
+    Stage::Detection,
+    Stage::Alert,
+    Stage::Total,
+  ];
+
+  pub fn label(&self) -> &'static str {
+    match self {
+      Stage::Capture => "Capture",
+      Stage::Queued => "Waiting for analysis",
+      Stage::Crop => "Crop",
+      Stage::Luma => "Grayscale",
+      Stage::Matching => "Matching",
+      Stage::Detection => "Detection (all)",
+      Stage::Alert => "Alert",
+      Stage::Total => "Total",
+    }
+  }
+
+  fn duration(&self, timings: &Timings) -> Duration {
+    match self {
+      Stage::Capture => timings.capture,
+      Stage::Queued => timings.queued,
+      Stage::Crop => timings.crop,
+      Stage::Luma => timings.luma,
+      Stage::Matching => timings.matching,
+      Stage::Detection => timings.detection,
+      Stage::Alert => timings.alert,
+      Stage::Total => timings.total,
+    }
+  }
+}
+
+/// Durations of a stage over the last frames
+#[derive(Default, Clone, Debug)]
+pub struct Histogram {
+  samples: VecDeque<Duration>,
+}
+
+impl Histogram {
+  pub fn record(&mut self, duration: Duration) {
+    if self.samples.len() == WINDOW {
+      self.samples.pop_front();
+    }
+    self.samples.push_back(duration);
+  }
+
+  /// Duration under which `quantile` (from 0.0 to 1.0) of the frames are,
+  /// None without frames
+  pub fn percentile(&self, quantile: f64) -> Option<Duration> {
+    let mut samples = self.samples.iter().copied().collect::<Vec<_>>();
+    samples.sort();
+    let index = ((samples.len() as f64 * quantile).ceil() as usize).clamp(1, samples.len().max(1)) - 1;
+    return samples.get(index).copied();
+  }
+}
+
+/// Timing histograms of the watcher pipeline, one per stage
+#[derive(Default, Clone, Debug)]
+pub struct Metrics {
+  histograms: [Histogram; Stage::ALL.len()],
+  frames: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct StageSummary {
+  pub stage: Stage,
+  pub p50_ms: f64,
+  pub p95_ms: f64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Summary {
+  /// Frames analyzed since the start, the percentiles only cover the last ones
+  pub frames: u64,
+  pub dropped_frames: u64,
+  pub stages: Vec<StageSummary>,
+}
+
+impl Metrics {
+  pub fn record(&mut self, timings: &Timings) {
+    for stage in Stage::ALL {
+      self.histograms[stage as usize].record(stage.duration(timings));
+    }
+    self.frames += 1;
+  }
+
+  pub fn histogram(&self, stage: Stage) -> &Histogram {
+    return &self.histograms[stage as usize];
+  }
+
+  /// Frames recorded, including the ones out of the window
+  pub fn frames(&self) -> u64 {
+    return self.frames;
+  }
+
+  /// p50 and p95 of each stage, empty before the first frame
+  pub fn summary(&self, dropped_frames: u64) -> Summary {
+    let milliseconds = |duration: Duration| duration.as_secs_f64() * 1000.0;
+    let stages = Stage::ALL
+      .iter()
+      .filter_map(|stage| {
+        let histogram = self.histogram(*stage);
+        Some(StageSummary {
+          stage: *stage,
+          p50_ms: milliseconds(histogram.percentile(0.5)?),
+          p95_ms: milliseconds(histogram.percentile(0.95)?),
+        })
+      })
+      .collect();
+
+    return Summary {
+      frames: self.frames,
+      dropped_frames,
+      stages,
+    };
+  }
+}
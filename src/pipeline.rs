@@ -0,0 +1,131 @@
+use parking_lot::{Condvar, Mutex};
+
+/// Hands frames from the capture thread to the analysis thread. It holds a
+/// single frame: a newer one replaces it, so the analysis always works on the
+/// latest capture and never falls behind.
+pub struct FrameSlot<T> {
+  slot: Mutex<Slot<T>>,
+  ready: Condvar,
+}
+
+struct Slot<T> {
+  value: Option<T>,
+  closed: bool,
+}
+
+impl<T> Default for FrameSlot<T> {
+  fn default() -> Self {
+    FrameSlot {
+      slot: Mutex::new(Slot { value: None, closed: false }),
+      ready: Condvar::new(),
+    }
+  }
+}
+
+impl<T> FrameSlot<T> {
+  /// Returns true if it replaced a frame that wasn't taken yet
+  pub fn put(&self, value: T) -> bool {
+    let replaced = self.slot.lock().value.replace(value).is_some();
+    self.ready.notify_one();
+    return replaced;
+  }
+
+  /// Waits for the next frame, None once closed
+  pub fn take(&self) -> Option<T> {
+    let mut slot = self.slot.lock();
+    loop {
+      if slot.closed {
+        return None;
+      }
+      if let Some(value) = slot.value.take() {
+        return Some(value);
+      }
+      self.ready.wait(&mut slot);
+    }
+  }
+
+  /// Takes the frame waiting, if any, without waiting for one
+  pub fn try_take(&self) -> Option<T> {
+    self.slot.lock().value.take()
+  }
+
+  /// Drops the frame waiting, if any
+  pub fn clear(&self) {
+    self.slot.lock().value = None;
+  }
+
+  /// Wakes up `take()` for good, the frame waiting is dropped
+  pub fn close(&self) {
+    let mut slot = self.slot.lock();
+    slot.closed = true;
+    slot.value = None;
+    self.ready.notify_all();
+  }
+
+  pub fn is_closed(&self) -> bool {
+    self.slot.lock().closed
+  }
+}
+
+/// Closes the slot when dropped, also when its thread panics
+pub struct CloseOnDrop<'a, T>(pub &'a FrameSlot<T>);
+
+impl<T> Drop for CloseOnDrop<'_, T> {
+  fn drop(&mut self) {
+    self.0.close();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::Arc;
+  use std::time::Duration;
+
+  use super::*;
+
+  #[test]
+  fn keeps_the_latest_frame() {
+    let slot = FrameSlot::default();
+    assert!(!slot.put(1));
+    assert!(slot.put(2));
+    assert_eq!(slot.take(), Some(2));
+    assert_eq!(slot.try_take(), None);
+  }
+
+  #[test]
+  fn clear_drops_the_frame() {
+    let slot = FrameSlot::default();
+    slot.put(1);
+    slot.clear();
+    assert_eq!(slot.try_take(), None);
+  }
+
+  #[test]
+  fn take_waits_for_a_frame() {
+    let slot = Arc::new(FrameSlot::default());
+    let producer = slot.clone();
+    let thread = std::thread::spawn(move || {
+      std::thread::sleep(Duration::from_millis(50));
+      producer.put(1);
+    });
+    assert_eq!(slot.take(), Some(1));
+    thread.join().unwrap();
+  }
+
+  #[test]
+  fn close_wakes_take_up() {
+    let slot = Arc::new(FrameSlot::<u32>::default());
+    let closer = slot.clone();
+    let thread = std::thread::spawn(move || {
+      std::thread::sleep(Duration::from_millis(50));
+      let _close = CloseOnDrop(&closer);
+    });
+    assert_eq!(slot.take(), None);
+    assert!(slot.is_closed());
+    thread.join().unwrap();
+
+    // Frames put after closing are never taken
+    slot.put(1);
+    assert_eq!(slot.take(), None);
+  }
+}
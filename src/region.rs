@@ -0,0 +1,179 @@
+use image::{DynamicImage, GenericImageView};
+use imageproc::contours::Contour;
+use serde::{Serialize, Deserialize};
+
+use crate::contour::{detect_squares, Square};
+
+/// Tunables of the villager icon selection
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct RegionSettings {
+  /// Luma under which pixels are black when stretching the contrast before
+  /// looking for contours
+  pub contrast_low: u8,
+  /// Luma above which pixels are white
+  pub contrast_high: u8,
+  /// Contours with fewer points aren't considered as squares
+  pub min_contour_points: usize,
+  /// Distance of contour points to the sides of a square, in pixels
+  pub square_tolerance: f64,
+  /// Pixels trimmed from each side of a detected square, to drop its border
+  pub square_offset: u32,
+  /// Pixels searched above and below the selected icon
+  pub search_margin: u32,
+  /// Icon widths searched on each side of the selected icon, the villager
+  /// isn't always first in the queue
+  pub search_slots: u32,
+}
+
+impl Default for RegionSettings {
+  fn default() -> RegionSettings {
+    RegionSettings {
+      contrast_low: 75,
+      contrast_high: 90,
+      min_contour_points: 150,
+      square_tolerance: 10.0,
+      square_offset: 5,
+      search_margin: 20,
+      search_slots: 4,
+    }
+  }
+}
+
+/// Rectangle in frame pixels
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rect {
+  pub x: u32,
+  pub y: u32,
+  pub width: u32,
+  pub height: u32,
+}
+
+impl Rect {
+  /// Rectangle between two opposite corners, in any order
+  pub fn from_corners(a: (u32, u32), b: (u32, u32)) -> Rect {
+    Rect {
+      x: a.0.min(b.0),
+      y: a.1.min(b.1),
+      width: a.0.abs_diff(b.0),
+      height: a.1.abs_diff(b.1),
+    }
+  }
+
+  pub fn bottom(&self) -> u32 {
+    self.y + self.height
+  }
+
+  pub fn right(&self) -> u32 {
+    self.x + self.width
+  }
+
+  pub fn contains(&self, other: &Rect) -> bool {
+    other.x >= self.x && other.y >= self.y && other.right() <= self.right() && other.bottom() <= self.bottom()
+  }
+
+  /// Moves and shrinks the rectangle to fit in an image of `width` by
+  /// `height`, keeping it at least one pixel wide
+  pub fn clamp(&self, width: u32, height: u32) -> Rect {
+    let x = self.x.min(width.saturating_sub(1));
+    let y = self.y.min(height.saturating_sub(1));
+    Rect {
+      x,
+      y,
+      width: self.width.clamp(1, (width - x).max(1)),
+      height: self.height.clamp(1, (height - y).max(1)),
+    }
+  }
+}
+
+pub struct Squares {
+  pub grayscale: image::GrayImage,
+  pub contours: Vec<Contour<i32>>,
+  pub squares: Vec<Square>,
+}
+
+/// Finds the square icons (production queue slots) in a window capture
+pub fn find_squares(source_image: &image::RgbaImage, settings: &RegionSettings) -> Squares {
+  let processing = source_image;
+  let processing = image::imageops::colorops::grayscale(processing);
+  let grayscale = processing.clone();
+  let processing = imageproc::contrast::stretch_contrast(
+    &processing,
+    settings.contrast_low,
+    settings.contrast_high,
+    0,
+    255,
+  );
+
+  let processed_image = processing;
+
+  let contours = imageproc::contours::find_contours::<i32>(&processed_image);
+
+  let squares = detect_squares(
+    processed_image.width(),
+    processed_image.height(),
+    &contours,
+    settings.min_contour_points,
+    settings.square_tolerance,
+  );
+
+  return Squares {
+    grayscale,
+    contours,
+    squares,
+  };
+}
+
+/// Crops the inside of a detected square out of the capture
+pub fn square_image(source_image: &image::RgbaImage, square: &Square, settings: &RegionSettings) -> Option<image::RgbaImage> {
+  let offset = settings.square_offset;
+  let rect = square_rect(square);
+  let width = rect.width.checked_sub(offset * 2).filter(|width| *width > 0)?;
+  let height = rect.height.checked_sub(offset * 2).filter(|height| *height > 0)?;
+  return Some(source_image.view(rect.x + offset, rect.y + offset, width, height).to_image());
+}
+
+/// Builds the needle from the selected square. Only part of the icon is
+/// kept, so the queue counter and progress bar don't affect matching.
+pub fn villager_needle(square_image: &image::RgbaImage) -> image::GrayImage {
+  // Select half the image
+  let image = square_image;
+  let image = image.view(
+    image.width() / 2,
+    10.min(image.height() / 2),
+    image.width() / 2,
+    image.height() / 2,
+  );
+  let image = image.to_image();
+  return DynamicImage::ImageRgba8(image).to_luma8();
+}
+
+/// Position of a detected square in the capture
+pub fn square_rect(square: &Square) -> Rect {
+  Rect::from_corners(
+    (square.points[0].x() as u32, square.points[0].y() as u32),
+    (square.points[2].x() as u32, square.points[2].y() as u32),
+  )
+}
+
+/// Search region around a selected icon, in a frame of `width` by `height`
+pub fn search_around(icon: &Rect, width: u32, height: u32, settings: &RegionSettings) -> Rect {
+  let x_margin = icon.width.saturating_mul(settings.search_slots);
+  let y_margin = settings.search_margin;
+  let x = icon.x.saturating_sub(x_margin);
+  let y = icon.y.saturating_sub(y_margin);
+  return Rect {
+    x,
+    y,
+    width: icon.right().saturating_add(x_margin).min(width) - x,
+    height: icon.bottom().saturating_add(y_margin).min(height) - y,
+  };
+}
+
+/// Builds the needle from a rectangle selected by hand, when no square could
+/// be detected
+pub fn rect_needle(source_image: &image::RgbaImage, rect: &Rect) -> image::GrayImage {
+  let rect = rect.clamp(source_image.width(), source_image.height());
+  let image = source_image.view(rect.x, rect.y, rect.width, rect.height).to_image();
+  return DynamicImage::ImageRgba8(image).to_luma8();
+}
@@ -0,0 +1,81 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use parking_lot::RwLock;
+
+use crate::config;
+use crate::state::{Interface, State};
+
+const INTERVAL: Duration = Duration::from_secs(1);
+
+/// Reloads the active profile when its file changes on disk, e.g. edited by
+/// a script or checked out from version control
+pub struct Reloader {
+  state: Arc<RwLock<State>>,
+  name: String,
+  modified: Option<SystemTime>,
+}
+
+impl Reloader {
+  pub fn spawn(state: Arc<RwLock<State>>) {
+    let name = state.read().config.name.clone();
+    let mut reloader = Reloader {
+      state,
+      modified: config::modified(&name),
+      name,
+    };
+
+    std::thread::spawn(move || {
+      loop {
+        std::thread::sleep(INTERVAL);
+        reloader.poll();
+      }
+    });
+  }
+
+  fn poll(&mut self) {
+    let (name, is_busy) = {
+      let state = self.state.read();
+      // Changes made in the settings screen would be lost
+      let is_busy = matches!(state.interface, Interface::Settings) || state.config_error.is_some();
+      (state.config.name.clone(), is_busy)
+    };
+
+    // Another profile was picked, start over from its file
+    if name != self.name {
+      self.modified = config::modified(&name);
+      self.name = name;
+      return;
+    }
+
+    let modified = config::modified(&name);
+    if is_busy || modified.is_none() || modified == self.modified {
+      return;
+    }
+    self.modified = modified;
+
+    eprintln!("reload: profile {} changed on disk", name);
+
+    let result = config::load(&name);
+    let mut state = self.state.write();
+    if state.config.name != name {
+      return;
+    }
+
+    match result {
+      Ok(config) => {
+        if state.civ.as_ref().map(|civ| config.needle(civ).is_none()).unwrap_or(false) {
+          state.civ = None;
+        }
+        if !config.is_configured() && matches!(state.interface, Interface::Main) {
+          state.interface = Interface::RegionSelect;
+        }
+        state.config = config;
+        state.villager_texture = None;
+        state.reload_error = None;
+      }
+      Err(err) => {
+        state.reload_error = Some(err.to_string());
+      }
+    }
+  }
+}
@@ -0,0 +1,184 @@
+use std::path::Path;
+use std::time::Duration;
+use serde::Serialize;
+
+use crate::detector::{DetectionStatus, MultiDetector, Profile};
+use crate::error::Result;
+use crate::source::{DirectorySource, FrameSource};
+
+/// Gap assumed between frames that don't carry a timestamp, same as the live watcher
+const DEFAULT_FRAME_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Serialize, Debug)]
+pub struct Interval {
+  pub start_ms: u64,
+  pub end_ms: u64,
+  pub status: DetectionStatus,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Timeline {
+  pub intervals: Vec<Interval>,
+  pub frames: usize,
+  pub duration_ms: u64,
+  pub idle_ms: u64,
+  /// Why each frame that couldn't be read was left out
+  #[serde(skip_serializing_if = "Vec::is_empty")]
+  pub skipped: Vec<String>,
+}
+
+/// Runs the detection on every frame of `directory` and merges consecutive
+/// frames with the same status into intervals. `profiles` are named, e.g.
+/// one per civilization, as for the live watcher.
+pub fn replay(directory: &Path, profiles: Vec<(String, Profile)>) -> Result<Timeline> {
+  let mut source = DirectorySource::new(directory)?;
+  let mut detector = MultiDetector::new(profiles);
+  let mut samples: Vec<(Duration, DetectionStatus)> = vec![];
+  let mut skipped = vec![];
+
+  loop {
+    let frame = match source.next_frame() {
+      Ok(Some(frame)) => frame,
+      Ok(None) => break,
+      Err(err) => {
+        skipped.push(err.to_string());
+        continue;
+      }
+    };
+    let timestamp = frame.timestamp.unwrap_or_else(|| {
+      samples.last().map(|(t, _)| *t + DEFAULT_FRAME_INTERVAL).unwrap_or(Duration::ZERO)
+    });
+    let detection = detector.detect(&frame.data);
+
+    samples.push((timestamp, detection.status));
+  }
+
+  // The timeline needs increasing timestamps, whatever the file names
+  samples.sort_by_key(|(timestamp, _)| *timestamp);
+
+  return Ok(Timeline { skipped, ..build_timeline(&samples) });
+}
+
+fn build_timeline(samples: &[(Duration, DetectionStatus)]) -> Timeline {
+  let mut intervals: Vec<Interval> = vec![];
+  let mut previous_gap = DEFAULT_FRAME_INTERVAL;
+
+  for (index, (start, status)) in samples.iter().enumerate() {
+    // Each frame stands for the time until the next one. The last frame is
+    // assumed to last as long as the gap before it.
+    let end = match samples.get(index + 1) {
+      Some((next, _)) => *next,
+      None => *start + previous_gap,
+    };
+    previous_gap = end.saturating_sub(*start);
+
+    let start_ms = start.as_millis() as u64;
+    let end_ms = end.as_millis() as u64;
+
+    match intervals.last_mut() {
+      Some(last) if last.status == *status => last.end_ms = end_ms,
+      _ => intervals.push(Interval { start_ms, end_ms, status: status.clone() }),
+    }
+  }
+
+  let duration_ms = match (intervals.first(), intervals.last()) {
+    (Some(first), Some(last)) => last.end_ms - first.start_ms,
+    _ => 0,
+  };
+  let idle_ms = intervals
+    .iter()
+    .filter(|i| i.status == DetectionStatus::NotQueued)
+    .map(|i| i.end_ms - i.start_ms)
+    .sum();
+
+  return Timeline {
+    intervals,
+    frames: samples.len(),
+    duration_ms,
+    idle_ms,
+    skipped: vec![],
+  };
+}
+
+impl Timeline {
+  pub fn to_json(&self) -> String {
+    serde_json::to_string_pretty(self).unwrap()
+  }
+
+  pub fn to_csv(&self) -> String {
+    let mut output = String::from("start_ms,end_ms,status,reason\n");
+    for interval in &self.intervals {
+      let status = match interval.status {
+        DetectionStatus::Queued => "queued",
+        DetectionStatus::NotQueued => "not_queued",
+        DetectionStatus::Unknown { .. } => "unknown",
+      };
+      let reason = match &interval.status {
+        DetectionStatus::Unknown { reason } => reason.as_str(),
+        _ => "",
+      };
+      output.push_str(&format!("{},{},{},{}\n", interval.start_ms, interval.end_ms, status, reason));
+    }
+    return output;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn samples(statuses: &[(u64, DetectionStatus)]) -> Vec<(Duration, DetectionStatus)> {
+    return statuses
+      .iter()
+      .map(|(ms, status)| (Duration::from_millis(*ms), status.clone()))
+      .collect();
+  }
+
+  #[test]
+  fn merges_frames_with_the_same_status() {
+    let timeline = build_timeline(&samples(&[
+      (0, DetectionStatus::Queued),
+      (500, DetectionStatus::Queued),
+      (1000, DetectionStatus::NotQueued),
+      (1500, DetectionStatus::NotQueued),
+      (2000, DetectionStatus::NotQueued),
+      (2500, DetectionStatus::Queued),
+    ]));
+
+    let intervals = timeline.intervals
+      .iter()
+      .map(|i| (i.start_ms, i.end_ms, i.status.clone()))
+      .collect::<Vec<_>>();
+    assert_eq!(intervals, vec![
+      (0, 1000, DetectionStatus::Queued),
+      (1000, 2500, DetectionStatus::NotQueued),
+      // The last frame lasts as long as the gap before it
+      (2500, 3000, DetectionStatus::Queued),
+    ]);
+    assert_eq!(timeline.frames, 6);
+    assert_eq!(timeline.duration_ms, 3000);
+    assert_eq!(timeline.idle_ms, 1500);
+  }
+
+  #[test]
+  fn unknown_frames_are_not_idle() {
+    let unknown = DetectionStatus::Unknown { reason: "frame is black".to_owned() };
+    let timeline = build_timeline(&samples(&[
+      (1000, DetectionStatus::NotQueued),
+      (2000, unknown.clone()),
+      (4000, DetectionStatus::NotQueued),
+    ]));
+
+    assert_eq!(timeline.intervals.len(), 3);
+    assert_eq!(timeline.duration_ms, 5000);
+    assert_eq!(timeline.idle_ms, 3000);
+  }
+
+  #[test]
+  fn empty_timeline() {
+    let timeline = build_timeline(&[]);
+    assert!(timeline.intervals.is_empty());
+    assert_eq!(timeline.duration_ms, 0);
+    assert_eq!(timeline.idle_ms, 0);
+  }
+}
@@ -1,33 +1,340 @@
-use std::time::Duration;
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::time::{Duration, SystemTime};
+use parking_lot::Mutex;
 
 use tinyaudio::prelude::*;
 
-pub fn play_tone(note: f32, duration: Duration) {
-  std::thread::spawn(move || {
-    let params = OutputDeviceParameters {
-      channels_count: 2,
-      sample_rate: 44100,
-      channel_sample_count: 4410,
+use crate::wav;
+
+const SAMPLE_RATE: usize = 44100;
+const CHANNELS: usize = 2;
+/// Length of the fade in and fade out of each cue, avoids clicks
+const FADE: Duration = Duration::from_millis(10);
+
+#[derive(Clone, Debug)]
+pub enum Waveform {
+  /// Sine wave, frequency in Hz
+  Tone(f32),
+  /// Mono samples at the engine's sample rate, e.g. a decoded sound file
+  Samples(Arc<[f32]>),
+}
+
+#[derive(Clone, Debug)]
+pub struct Cue {
+  pub waveform: Waveform,
+  /// From 0.0 to 1.0
+  pub volume: f32,
+  /// Length of a tone. Samples always play to their end.
+  pub duration: Duration,
+}
+
+impl Cue {
+  /// How long the cue plays, if it isn't stopped
+  pub fn length(&self) -> Duration {
+    match &self.waveform {
+      Waveform::Tone(_) => self.duration,
+      Waveform::Samples(samples) => Duration::from_secs_f64(samples.len() as f64 / SAMPLE_RATE as f64),
+    }
+  }
+}
+
+#[derive(Clone, Debug)]
+pub enum AudioCommand {
+  Play(Cue),
+  /// Fade out every playing cue
+  Stop,
+}
+
+/// Where the watcher sends its cues. `at` is the watcher's clock, which
+/// follows the frame timestamps for recorded frames; live sinks ignore it.
+pub trait AudioSink: Send + Sync {
+  fn play(&self, cue: Cue, at: Duration);
+  /// Stops every playing cue, with a short fade out
+  fn stop(&self, at: Duration);
+}
+
+struct Voice {
+  cue: Cue,
+  phase: f32,
+  position: usize,
+  length: usize,
+}
+
+/// Mixes the playing cues together
+struct Mixer {
+  receiver: Option<Receiver<AudioCommand>>,
+  voices: Vec<Voice>,
+  fade: usize,
+}
+
+impl Mixer {
+  fn new(receiver: Option<Receiver<AudioCommand>>) -> Self {
+    Mixer {
+      receiver,
+      voices: vec![],
+      fade: samples(FADE),
+    }
+  }
+
+  fn handle(&mut self, command: AudioCommand) {
+    match command {
+      AudioCommand::Play(cue) => {
+        // Sounds longer than the repeat interval would pile up otherwise
+        if self.is_playing(&cue.waveform) {
+          return;
+        }
+        let length = samples(cue.length());
+        self.voices.push(Voice { cue, phase: 0.0, position: 0, length });
+      }
+      AudioCommand::Stop => {
+        for voice in self.voices.iter_mut() {
+          voice.length = voice.length.min(voice.position + self.fade);
+        }
+      }
+    }
+  }
+
+  /// Whether the samples of `waveform` are still playing, tones can overlap
+  fn is_playing(&self, waveform: &Waveform) -> bool {
+    let Waveform::Samples(samples) = waveform else {
+      return false;
     };
+    return self.voices.iter().any(|voice| {
+      let is_same = matches!(&voice.cue.waveform, Waveform::Samples(other) if Arc::ptr_eq(other, samples));
+      is_same && voice.position < voice.length
+    });
+  }
 
-    let device =
-      run_output_device(params, {
-        let mut clock = 0f32;
-        move |data| {
-          for samples in data.chunks_mut(params.channels_count) {
-            clock = (clock + 1.0) % params.sample_rate as f32;
-            let value =
-              (clock * note * 2.0 * std::f32::consts::PI / params.sample_rate as f32).sin();
-            for sample in samples {
-              *sample = value;
-            }
-          }
+  /// Fills `data` with interleaved samples
+  fn render(&mut self, data: &mut [f32]) {
+    while let Some(Ok(command)) = self.receiver.as_ref().map(|r| r.try_recv()) {
+      self.handle(command);
+    }
+
+    for frame in data.chunks_mut(CHANNELS) {
+      let mut value = 0.0;
+
+      for voice in self.voices.iter_mut() {
+        if voice.position >= voice.length {
+          continue;
         }
-      }).unwrap();
 
-    std::thread::sleep(duration);
+        let fade_in = voice.position as f32 / self.fade as f32;
+        let fade_out = (voice.length - voice.position) as f32 / self.fade as f32;
+        let envelope = fade_in.min(fade_out).min(1.0);
 
-    drop(device);
-  });
+        let sample = match &voice.cue.waveform {
+          Waveform::Tone(note) => {
+            let sample = (voice.phase * 2.0 * std::f32::consts::PI).sin();
+            voice.phase = (voice.phase + note / SAMPLE_RATE as f32) % 1.0;
+            sample
+          }
+          Waveform::Samples(samples) => samples[voice.position],
+        };
+
+        value += voice.cue.volume * envelope * sample;
+        voice.position += 1;
+      }
+
+      let value = value.clamp(-1.0, 1.0);
+      for sample in frame {
+        *sample = value;
+      }
+    }
+
+    self.voices.retain(|voice| voice.position < voice.length);
+  }
 }
 
+/// Keeps one output device open for the lifetime of the app and plays cues
+/// sent to it, possibly overlapping.
+pub struct AudioEngine {
+  sender: Sender<AudioCommand>,
+  _device: Mutex<Box<dyn BaseAudioOutputDevice>>,
+}
+
+impl AudioEngine {
+  pub fn new() -> Result<Self, Box<dyn Error>> {
+    let params = OutputDeviceParameters {
+      channels_count: CHANNELS,
+      sample_rate: SAMPLE_RATE,
+      channel_sample_count: 1024,
+    };
+
+    let (sender, receiver) = channel();
+    let mut mixer = Mixer::new(Some(receiver));
+
+    let device = run_output_device(params, move |data| mixer.render(data))?;
+
+    return Ok(AudioEngine {
+      sender,
+      _device: Mutex::new(device),
+    });
+  }
+}
+
+impl AudioSink for AudioEngine {
+  fn play(&self, cue: Cue, _at: Duration) {
+    let _ = self.sender.send(AudioCommand::Play(cue));
+  }
+
+  fn stop(&self, _at: Duration) {
+    let _ = self.sender.send(AudioCommand::Stop);
+  }
+}
+
+#[derive(Clone, Debug)]
+pub struct Recorded {
+  pub at: Duration,
+  pub command: AudioCommand,
+}
+
+/// Keeps the cues in memory instead of playing them, they can then be
+/// inspected or rendered to a WAV file.
+#[derive(Default)]
+pub struct RecordingSink {
+  events: Mutex<Vec<Recorded>>,
+}
+
+impl RecordingSink {
+  pub fn new() -> Self {
+    Default::default()
+  }
+
+  pub fn events(&self) -> Vec<Recorded> {
+    self.events.lock().clone()
+  }
+
+  /// Mixes the recorded cues as the audio engine would have played them,
+  /// returns interleaved samples.
+  pub fn render(&self) -> Vec<f32> {
+    let events = self.events();
+    let end = events
+      .iter()
+      .map(|event| match &event.command {
+        AudioCommand::Play(cue) => event.at + cue.length(),
+        AudioCommand::Stop => event.at,
+      })
+      .max()
+      .unwrap_or_default();
+
+    let mut mixer = Mixer::new(None);
+    let mut output = vec![0.0; samples(end) * CHANNELS];
+    let mut position = 0;
+
+    for event in events {
+      let until = (samples(event.at) * CHANNELS).clamp(position, output.len());
+      mixer.render(&mut output[position..until]);
+      position = until;
+      mixer.handle(event.command);
+    }
+    mixer.render(&mut output[position..]);
+
+    return output;
+  }
+
+  pub fn write_wav(&self, path: &Path) -> std::io::Result<()> {
+    let bytes = wav::encode(SAMPLE_RATE as u32, CHANNELS as u16, &self.render());
+    return std::fs::write(path, bytes);
+  }
+
+  fn push(&self, at: Duration, command: AudioCommand) {
+    self.events.lock().push(Recorded { at, command });
+  }
+}
+
+impl AudioSink for RecordingSink {
+  fn play(&self, cue: Cue, at: Duration) {
+    self.push(at, AudioCommand::Play(cue));
+  }
+
+  fn stop(&self, at: Duration) {
+    self.push(at, AudioCommand::Stop);
+  }
+}
+
+/// Decodes a sound file, or returns it from the cache if it was already loaded
+/// and hasn't been modified since
+pub fn load_sound(path: &Path) -> std::io::Result<Arc<[f32]>> {
+  /// Modification time of the file and its samples
+  type Cached = (Option<SystemTime>, Arc<[f32]>);
+  static SOUNDS: OnceLock<Mutex<HashMap<PathBuf, Cached>>> = OnceLock::new();
+  let sounds = SOUNDS.get_or_init(Default::default);
+
+  let modified = std::fs::metadata(path)?.modified().ok();
+  if let Some((_, samples)) = sounds.lock().get(path).filter(|(time, _)| *time == modified) {
+    return Ok(samples.clone());
+  }
+
+  let samples: Arc<[f32]> = load_file(path)?.into();
+  sounds.lock().insert(path.to_owned(), (modified, samples.clone()));
+
+  return Ok(samples);
+}
+
+/// Decodes a sound file to mono samples at the engine's sample rate. Only WAV
+/// files are supported.
+pub fn load_file(path: &Path) -> std::io::Result<Vec<f32>> {
+  let is_wav = path.extension().map(|e| e.eq_ignore_ascii_case("wav")).unwrap_or(false);
+  if !is_wav {
+    return Err(std::io::Error::new(
+      std::io::ErrorKind::Unsupported,
+      format!("{:?}: only WAV files are supported", path),
+    ));
+  }
+
+  let bytes = std::fs::read(path)?;
+  let wav = wav::decode(&bytes)?;
+
+  return Ok(wav.to_mono(SAMPLE_RATE as u32));
+}
+
+fn samples(duration: Duration) -> usize {
+  (duration.as_secs_f64() * SAMPLE_RATE as f64) as usize
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn sound(samples: Arc<[f32]>) -> Cue {
+    return Cue { waveform: Waveform::Samples(samples), volume: 1.0, duration: Duration::from_millis(200) };
+  }
+
+  #[test]
+  fn sounds_are_not_restarted_while_playing() {
+    let samples: Arc<[f32]> = vec![0.5; SAMPLE_RATE].into();
+    let recording = RecordingSink::new();
+    recording.play(sound(samples.clone()), Duration::ZERO);
+    recording.play(sound(samples.clone()), Duration::from_millis(500));
+
+    let output = recording.render();
+    assert!(output.iter().all(|sample| *sample <= 0.5), "the sound overlapped itself");
+    assert!(output[SAMPLE_RATE * CHANNELS..].iter().all(|sample| *sample == 0.0));
+
+    // Played again once it's over
+    recording.play(sound(samples), Duration::from_millis(1500));
+    let output = recording.render();
+    assert!(output[SAMPLE_RATE * CHANNELS * 2..].iter().all(|sample| *sample > 0.0));
+  }
+
+  #[test]
+  fn modified_sounds_are_reloaded() {
+    let path = std::env::temp_dir().join(format!("aoe4-villager-enforcer-sound-{}.wav", std::process::id()));
+    std::fs::write(&path, wav::encode(SAMPLE_RATE as u32, 1, &[0.5; 10])).unwrap();
+    assert_eq!(load_sound(&path).unwrap().len(), 10);
+
+    std::fs::write(&path, wav::encode(SAMPLE_RATE as u32, 1, &[0.5; 20])).unwrap();
+    let file = std::fs::File::options().write(true).open(&path).unwrap();
+    file.set_modified(SystemTime::now() + Duration::from_secs(60)).unwrap();
+    drop(file);
+    let samples = load_sound(&path);
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(samples.unwrap().len(), 20);
+  }
+}
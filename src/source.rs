@@ -0,0 +1,152 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use xcap::Window;
+
+use crate::error::{Error, Result};
+
+pub struct Frame {
+  pub data: image::RgbaImage,
+  /// Position of the frame in the recording, if the source knows it
+  pub timestamp: Option<Duration>,
+}
+
+/// Something the watcher can pull frames from. Returning `None` means the
+/// source is gone (window closed, recording exhausted), an error only means
+/// this frame couldn't be taken.
+pub trait FrameSource: Send + 'static {
+  fn next_frame(&mut self) -> Result<Option<Frame>>;
+}
+
+impl FrameSource for Box<dyn FrameSource> {
+  fn next_frame(&mut self) -> Result<Option<Frame>> {
+    (**self).next_frame()
+  }
+}
+
+/// Live capture of an xcap window
+pub struct WindowSource {
+  window_id: u32,
+}
+
+impl WindowSource {
+  pub fn new(window_id: u32) -> Self {
+    WindowSource { window_id }
+  }
+}
+
+impl FrameSource for WindowSource {
+  fn next_frame(&mut self) -> Result<Option<Frame>> {
+    let window = Window::all()?
+      .into_iter()
+      .find(|window| window.id() == self.window_id);
+    let Some(window) = window else {
+      return Ok(None);
+    };
+
+    let data = window.capture_image()?;
+
+    return Ok(Some(Frame {
+      data,
+      timestamp: None,
+    }));
+  }
+}
+
+/// Directory of PNG screenshots. Files named after a number (e.g. `1500.png`)
+/// use it as their timestamp in milliseconds and are played back in that
+/// order, the other ones in file name order.
+pub struct DirectorySource {
+  paths: Vec<PathBuf>,
+  index: usize,
+}
+
+impl DirectorySource {
+  pub fn new(directory: &Path) -> Result<Self> {
+    let mut paths = vec![];
+
+    for entry in fs::read_dir(directory)? {
+      let path = entry?.path();
+      let is_png = path.extension().map(|e| e.eq_ignore_ascii_case("png")).unwrap_or(false);
+      if path.is_file() && is_png {
+        paths.push(path);
+      }
+    }
+
+    // `500.png` comes before `1000.png` even without zero-padding
+    paths.sort_by(|a, b| timestamp_from_path(a).cmp(&timestamp_from_path(b)).then_with(|| a.cmp(b)));
+
+    return Ok(DirectorySource { paths, index: 0 });
+  }
+}
+
+impl FrameSource for DirectorySource {
+  /// A file that can't be decoded is an error, the next call moves on to
+  /// the following one
+  fn next_frame(&mut self) -> Result<Option<Frame>> {
+    let Some(path) = self.paths.get(self.index) else {
+      return Ok(None);
+    };
+    self.index += 1;
+
+    let image = image::open(path).map_err(|err| Error::invalid(path, err))?;
+
+    return Ok(Some(Frame {
+      data: image.to_rgba8(),
+      timestamp: timestamp_from_path(path),
+    }));
+  }
+}
+
+/// A single still image, yielded once
+pub struct ImageSource {
+  image: Option<image::RgbaImage>,
+}
+
+impl ImageSource {
+  pub fn new(image: image::RgbaImage) -> Self {
+    ImageSource { image: Some(image) }
+  }
+
+  pub fn open(path: &Path) -> image::ImageResult<Self> {
+    return Ok(ImageSource::new(image::open(path)?.to_rgba8()));
+  }
+}
+
+impl FrameSource for ImageSource {
+  fn next_frame(&mut self) -> Result<Option<Frame>> {
+    return Ok(self.image.take().map(|data| Frame {
+      data,
+      timestamp: Some(Duration::ZERO),
+    }));
+  }
+}
+
+fn timestamp_from_path(path: &Path) -> Option<Duration> {
+  let stem = path.file_stem()?.to_str()?;
+  let millis = stem.parse::<u64>().ok()?;
+  return Some(Duration::from_millis(millis));
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn plays_numbered_frames_in_timestamp_order() {
+    let directory = std::env::temp_dir().join(format!("aoe4-villager-enforcer-source-{}", std::process::id()));
+    fs::create_dir_all(&directory).unwrap();
+    for name in ["1000.png", "500.png", "0250.png"] {
+      image::RgbaImage::new(1, 1).save(directory.join(name)).unwrap();
+    }
+
+    let mut source = DirectorySource::new(&directory).unwrap();
+    let mut timestamps = vec![];
+    while let Some(frame) = source.next_frame().unwrap() {
+      timestamps.push(frame.timestamp.unwrap().as_millis());
+    }
+    fs::remove_dir_all(&directory).unwrap();
+
+    assert_eq!(timestamps, vec![250, 500, 1000]);
+  }
+}
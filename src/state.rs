@@ -1,14 +1,21 @@
+use std::sync::Arc;
 use std::time::Instant;
 use egui::TextureHandle;
+use aoe4_villager_enforcer::detector::DetectionStatus;
+use aoe4_villager_enforcer::error::Result;
 
-use crate::config::Config;
+use crate::config::{self, Config};
 use crate::capture::Capture;
+use crate::events::WatcherEvent;
+use crate::metrics::Metrics;
+use crate::watcher::WatcherMode;
 
 #[derive(Copy, Clone, Debug)]
 pub enum Interface {
   WindowSelect,
   RegionSelect,
   Main,
+  Settings,
 }
 
 pub struct State {
@@ -19,5 +26,167 @@ pub struct State {
   pub config: Config,
   pub villager_texture: Option<TextureHandle>,
   pub is_watching: bool,
-  pub is_queued: bool,
+  /// Watching was paused, it resumes with the same idle time
+  pub is_paused: bool,
+  pub status: DetectionStatus,
+  /// Civilization whose villager image matched
+  pub civ: Option<String>,
+  /// Name of the current alert stage, while alerting
+  pub alert_stage: Option<String>,
+  /// Why the profile couldn't be loaded, `config` is empty meanwhile
+  pub config_error: Option<String>,
+  /// Why the profile changed on disk couldn't be reloaded, `config` is the
+  /// previous one meanwhile
+  pub reload_error: Option<String>,
+  /// Last error listing the windows, watching the game or saving the
+  /// profile. Capture errors are cleared once a frame is captured again,
+  /// save errors once the profile is saved.
+  pub last_error: Option<String>,
+  /// `last_error` is about saving the profile
+  pub is_save_error: bool,
+  /// Timings of the frames analyzed since the watcher started
+  pub metrics: Metrics,
+  /// Frames the analysis couldn't keep up with, since the watcher started
+  pub dropped_frames: u64,
+}
+
+impl State {
+  pub fn new(config: Config) -> State {
+    State {
+      interface: Interface::WindowSelect,
+      captures: None,
+      window_capture: None,
+      last_capture: Instant::now(),
+      config,
+      villager_texture: None,
+      is_watching: false,
+      is_paused: false,
+      status: DetectionStatus::Unknown { reason: "not checked yet".to_owned() },
+      civ: None,
+      alert_stage: None,
+      config_error: None,
+      reload_error: None,
+      last_error: None,
+      is_save_error: false,
+      metrics: Metrics::default(),
+      dropped_frames: 0,
+    }
+  }
+
+  /// Starts on the profile named `name`, or on an empty one with the error
+  /// if it can't be loaded
+  pub fn open(name: &str) -> State {
+    match config::load(name) {
+      Ok(config) => State::new(config),
+      Err(err) => {
+        let mut state = State::new(Config { name: name.to_owned(), ..Default::default() });
+        state.config_error = Some(err.to_string());
+        state
+      }
+    }
+  }
+
+  /// Replaces the config by another profile, and goes to the villager
+  /// selection if it isn't configured yet
+  pub fn switch_profile(&mut self, config: Config) {
+    self.config_error = None;
+    self.reload_error = None;
+    self.interface = if config.is_configured() { Interface::Main } else { Interface::RegionSelect };
+    self.config = config;
+    self.villager_texture = None;
+    self.civ = None;
+    self.alert_stage = None;
+  }
+
+  /// Loads the profile named `name` and switches to it
+  pub fn load_profile(&mut self, name: &str) {
+    match config::load(name) {
+      Ok(config) => self.switch_profile(config),
+      Err(err) => {
+        self.switch_profile(Config { name: name.to_owned(), ..Default::default() });
+        self.config_error = Some(err.to_string());
+      }
+    }
+  }
+
+  /// Shows the error of capturing the game, or clears the previous one once
+  /// it works again. Save errors are kept until the profile is saved.
+  pub fn set_capture_error(&mut self, error: Option<String>) {
+    if error.is_some() || !self.is_save_error {
+      self.last_error = error;
+      self.is_save_error = false;
+    }
+  }
+
+  /// Shows why the profile couldn't be saved, until it is. Returns whether
+  /// it was.
+  pub fn saved(&mut self, result: Result<()>) -> bool {
+    match result {
+      Ok(()) => {
+        if self.is_save_error {
+          self.last_error = None;
+          self.is_save_error = false;
+        }
+        return true;
+      }
+      Err(err) => {
+        self.last_error = Some(format!("Could not save the profile: {}", err));
+        self.is_save_error = true;
+        return false;
+      }
+    }
+  }
+
+  /// Shows a frame captured by the watcher
+  pub fn show_frame(&mut self, frame: Arc<image::RgbaImage>) {
+    if let Some(capture) = self.window_capture.as_mut() {
+      capture.data = Arc::unwrap_or_clone(frame);
+      capture.texture = None;
+    }
+  }
+
+  /// Follows what the watcher publishes
+  pub fn apply(&mut self, event: WatcherEvent) {
+    match event {
+      WatcherEvent::ModeChanged(mode) => {
+        if !self.is_watching && mode == WatcherMode::Running {
+          self.metrics = Metrics::default();
+          self.dropped_frames = 0;
+        }
+        self.is_watching = mode != WatcherMode::Stopped;
+        self.is_paused = mode == WatcherMode::Paused;
+        if mode == WatcherMode::Stopped {
+          self.alert_stage = None;
+        }
+      }
+      WatcherEvent::FrameCaptured => {
+        self.set_capture_error(None);
+      }
+      WatcherEvent::DetectionChanged { detection, civ } => {
+        // The alert stops once a villager is queued or the panel is hidden
+        if detection.status != DetectionStatus::NotQueued {
+          self.alert_stage = None;
+        }
+        self.status = detection.status;
+        if self.civ != civ {
+          self.civ = civ;
+          self.villager_texture = None;
+        }
+      }
+      WatcherEvent::AlertFired { stage } => {
+        self.alert_stage = Some(stage);
+      }
+      WatcherEvent::FrameAnalyzed { timings, dropped } => {
+        self.metrics.record(&timings);
+        self.dropped_frames = dropped;
+      }
+      WatcherEvent::WindowLost => {
+        self.window_capture = None;
+        self.interface = Interface::WindowSelect;
+      }
+      WatcherEvent::Error(message) => {
+        self.set_capture_error(Some(message));
+      }
+    }
+  }
 }
@@ -0,0 +1,24 @@
+//! Images shared by the detector and watcher tests. Declared by both the
+//! library and the app, so it only depends on the `image` crate.
+
+use image::{DynamicImage, GrayImage, Luma, Rgba, RgbaImage};
+
+/// 12x12 checkerboard of `size` pixel squares
+pub fn needle(size: u32) -> GrayImage {
+  return GrayImage::from_fn(12, 12, |x, y| Luma([if (x / size + y / size).is_multiple_of(2) { 0 } else { 255 }]));
+}
+
+/// Smooth 200x200 background, with enough contrast to not look like a menu
+pub fn background() -> RgbaImage {
+  return RgbaImage::from_fn(200, 200, |x, y| {
+    let value = ((x + y) % 64 + 64) as u8;
+    Rgba([value, value, value, 255])
+  });
+}
+
+/// `background()` with `needle` at (20, 150), in the bottom left quarter
+pub fn frame(needle: &GrayImage) -> RgbaImage {
+  let mut frame = background();
+  image::imageops::overlay(&mut frame, &DynamicImage::ImageLuma8(needle.clone()).to_rgba8(), 20, 150);
+  return frame;
+}
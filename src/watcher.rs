@@ -1,114 +1,820 @@
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::sync::Arc;
-use parking_lot::RwLock;
-use image::DynamicImage;
-use image::GenericImageView;
-use find_subimage::SubImageFinderState;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::thread::JoinHandle;
+use parking_lot::{Mutex, RwLock};
+use aoe4_villager_enforcer::detector::{Detection, DetectionStatus, MultiDetector};
+use aoe4_villager_enforcer::error::Result;
+use aoe4_villager_enforcer::source::FrameSource;
 
 use crate::State;
-use crate::Interface;
-use crate::capture;
-use crate::sound;
+use crate::config::{AlertSettings, AlertStage};
+use crate::events::{Broadcast, WatcherEvent};
+use crate::pipeline::{CloseOnDrop, FrameSlot};
+use crate::sound::{self, AudioEngine, AudioSink, Cue, Waveform};
 
-const NOTE: f32 = 12_800.0;
+/// Wait before starting the watcher thread again after it panicked
+const RESTART_DELAY: Duration = Duration::from_secs(1);
 
-#[derive(Clone)]
-pub struct Watcher {
+pub struct Watcher<S: FrameSource> {
+  /// Only read for the config, the watcher reports through `events`
   pub state: Arc<RwLock<State>>,
+  source: Arc<Mutex<Option<S>>>,
+  tracking: Arc<Mutex<Tracking>>,
+  events: Arc<Broadcast<WatcherEvent>>,
+  /// Kept between frames for the needles it resized, rebuilt when the
+  /// profile changes
+  detector: Arc<Mutex<Option<MultiDetector>>>,
+  /// Latest frame captured, until the app takes it to show it
+  frame: Arc<FrameSlot<Arc<image::RgbaImage>>>,
+  started: Instant,
+  /// None for headless runs, or if no output device could be opened
+  audio: Option<Arc<dyn AudioSink>>,
+  /// None for headless runs, the commands are applied right away
+  commands: Option<Sender<WatcherCommand>>,
+  /// Thread restarting the watcher thread, until the watcher is shut down
+  supervisor: Arc<Mutex<Option<JoinHandle<()>>>>,
 }
 
-impl Watcher {
+/// What the watcher thread can be told to do
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum WatcherCommand {
+  /// Checks a frame every interval
+  Start,
+  /// Stops checking and forgets the idle time
+  Stop,
+  /// Stops checking until started again, the idle time is kept
+  Pause,
+  /// Ends the thread, the watcher can't be started again
+  Shutdown,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum WatcherMode {
+  #[default]
+  Stopped,
+  Running,
+  Paused,
+}
+
+/// What the watcher remembers between frames
+#[derive(Default, Debug)]
+struct Tracking {
+  mode: WatcherMode,
+  idle: IdleTracker,
+  /// Civilization locked onto
+  civ: Option<String>,
+  /// Last published status, only changes are published
+  status: Option<DetectionStatus>,
+  /// Frames replaced by a newer one before they were analyzed
+  dropped: u64,
+}
+
+/// A frame waiting for the analysis
+struct Captured {
+  frame: Arc<image::RgbaImage>,
+  timestamp: Option<Duration>,
+  /// When the capture started
+  started: Instant,
+  /// How long the capture took
+  capture: Duration,
+}
+
+/// How long each stage of a frame took
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Timings {
+  pub capture: Duration,
+  /// Between the end of the capture and the start of the analysis
+  pub queued: Duration,
+  /// Steps of the detection, `detection` also includes setting it up
+  pub crop: Duration,
+  pub luma: Duration,
+  pub matching: Duration,
+  pub detection: Duration,
+  /// Playing the alert, if any
+  pub alert: Duration,
+  /// From the start of the capture to the end of the analysis
+  pub total: Duration,
+}
+
+/// Result of checking one frame
+#[derive(Debug)]
+pub struct Sample {
+  pub timestamp: Option<Duration>,
+  pub detection: Detection,
+  /// Civilization locked onto
+  pub civ: Option<String>,
+  /// Name of the alert stage played for this frame
+  pub alert: Option<String>,
+  pub timings: Timings,
+}
+
+/// Debounces the detection results, so a single missed frame or the short
+/// gap between two villagers doesn't trigger the alert, then escalates
+/// through the alert stages while the TC stays idle.
+#[derive(Default, Debug)]
+pub struct IdleTracker {
+  misses: u32,
+  idle_since: Option<Duration>,
+  alert_since: Option<Duration>,
+  stage: Option<usize>,
+  last_played: Option<Duration>,
+  /// When the watcher was paused, the paused time doesn't count as idle
+  paused_at: Option<Duration>,
+}
+
+impl IdleTracker {
+  /// Feeds the status of a frame taken at `now` (any monotonic clock),
+  /// returns the alert stage to play, if any.
+  pub fn update(&mut self, status: &DetectionStatus, settings: &AlertSettings, now: Duration) -> Option<AlertStage> {
+    match status {
+      DetectionStatus::NotQueued => {
+        self.misses += 1;
+        let idle_since = *self.idle_since.get_or_insert(now);
+        let idle = now.saturating_sub(idle_since);
+
+        if self.misses < settings.misses || idle < Duration::from_millis(settings.grace_ms) {
+          return None;
+        }
+
+        let alert_since = *self.alert_since.get_or_insert(now);
+        let elapsed = now.saturating_sub(alert_since).as_millis() as u64;
+
+        // The stages can be in any order, e.g. after editing their delays
+        let (index, stage) = settings.stages
+          .iter()
+          .enumerate()
+          .filter(|(_, stage)| stage.delay_ms <= elapsed)
+          .max_by_key(|(_, stage)| stage.delay_ms)?;
+
+        let escalated = self.stage != Some(index);
+        let is_due = self.last_played
+          .map(|last| now.saturating_sub(last) >= Duration::from_millis(stage.repeat_ms))
+          .unwrap_or(true);
+
+        if !escalated && !is_due {
+          return None;
+        }
+
+        self.stage = Some(index);
+        self.last_played = Some(now);
+
+        return Some(stage.clone());
+      }
+      // Unknown frames can't tell us anything, start over once the game is back
+      DetectionStatus::Queued | DetectionStatus::Unknown { .. } => {
+        *self = IdleTracker::default();
+        return None;
+      }
+    }
+  }
+
+  /// Index of the current alert stage, None while not alerting
+  pub fn stage(&self) -> Option<usize> {
+    self.stage
+  }
+
+  /// Stops the clock at `now`, until `resume()`
+  pub fn pause(&mut self, now: Duration) {
+    self.paused_at.get_or_insert(now);
+  }
+
+  /// Shifts the timestamps by the time paused, so the idle time and the
+  /// alert repeats carry on from where they were
+  pub fn resume(&mut self, now: Duration) {
+    let Some(paused_at) = self.paused_at.take() else {
+      return;
+    };
+    let paused = now.saturating_sub(paused_at);
+    for timestamp in [&mut self.idle_since, &mut self.alert_since, &mut self.last_played] {
+      if let Some(timestamp) = timestamp.as_mut() {
+        *timestamp += paused;
+      }
+    }
+  }
+}
+
+impl<S: FrameSource> Clone for Watcher<S> {
+  fn clone(&self) -> Self {
+    Watcher {
+      state: self.state.clone(),
+      source: self.source.clone(),
+      tracking: self.tracking.clone(),
+      events: self.events.clone(),
+      detector: self.detector.clone(),
+      frame: self.frame.clone(),
+      started: self.started,
+      audio: self.audio.clone(),
+      commands: self.commands.clone(),
+      supervisor: self.supervisor.clone(),
+    }
+  }
+}
+
+impl<S: FrameSource> Watcher<S> {
+  /// Creates a watcher driven by a thread, stopped until `start()` is called
   pub fn new(state: Arc<RwLock<State>>) -> Self {
+    let (sender, receiver) = channel();
     let watcher = Watcher {
       state: state.clone(),
+      source: Arc::new(Mutex::new(None)),
+      tracking: Default::default(),
+      events: Default::default(),
+      detector: Default::default(),
+      frame: Default::default(),
+      started: Instant::now(),
+      audio: match AudioEngine::new() {
+        Ok(audio) => Some(Arc::new(audio) as Arc<dyn AudioSink>),
+        Err(err) => {
+          eprintln!("Could not open audio output: {}", err);
+          None
+        }
+      },
+      commands: Some(sender),
+      supervisor: Default::default(),
     };
 
-    let mut watcher_thread = watcher.clone();
-    std::thread::spawn(move || {
-      loop {
-        println!("CHECK");
-        watcher_thread.check();
+    let supervised = watcher.clone();
+    let receiver = Arc::new(Mutex::new(receiver));
+    *watcher.supervisor.lock() = Some(std::thread::spawn(move || supervised.supervise(receiver)));
+
+    return watcher;
+  }
+
+  /// Runs the capture and analysis threads, and starts them again if one
+  /// of them panics
+  fn supervise(self, commands: Arc<Mutex<Receiver<WatcherCommand>>>) {
+    loop {
+      let slot = Arc::new(FrameSlot::default());
+
+      let analysis = self.clone();
+      let analysis_slot = slot.clone();
+      let analysis_thread = std::thread::Builder::new()
+        .name("watcher-analysis".to_owned())
+        .spawn(move || analysis.analyze_frames(&analysis_slot));
+      let analysis_thread = match analysis_thread {
+        Ok(thread) => thread,
+        Err(err) => {
+          eprintln!("watcher: could not start the analysis thread: {}", err);
+          std::thread::sleep(RESTART_DELAY);
+          continue;
+        }
+      };
 
-        std::thread::sleep(Duration::from_secs(1));
+      let mut capture = self.clone();
+      let capture_commands = commands.clone();
+      let capture_slot = slot.clone();
+      let capture_thread = std::thread::Builder::new()
+        .name("watcher-capture".to_owned())
+        .spawn(move || capture.capture_frames(&capture_commands.lock(), &capture_slot));
+      let capture_thread = match capture_thread {
+        Ok(thread) => thread,
+        Err(err) => {
+          eprintln!("watcher: could not start the capture thread: {}", err);
+          slot.close();
+          let _ = analysis_thread.join();
+          std::thread::sleep(RESTART_DELAY);
+          continue;
+        }
+      };
+
+      let is_shutdown = capture_thread.join();
+      slot.close();
+      let analysis = analysis_thread.join();
+      if let (Ok(true), Ok(())) = (is_shutdown, analysis) {
+        return;
       }
-    });
 
-    return watcher;
+      eprintln!("watcher: thread panicked, restarting it");
+      // It may have been left halfway through a frame
+      *self.detector.lock() = None;
+      self.events.publish(WatcherEvent::Error("The watcher crashed and was restarted".to_owned()));
+      std::thread::sleep(RESTART_DELAY);
+    }
   }
-  
-  fn check(&mut self) {
-    println!("check: lock");
-    let state = self.state.read();
-    if state.window_capture.is_none() {
-      return;
+
+  /// Captures a frame every interval while started, for the analysis thread,
+  /// and only waits for commands otherwise. Returns true on shutdown, false
+  /// if the analysis thread stopped.
+  fn capture_frames(&mut self, commands: &Receiver<WatcherCommand>, slot: &FrameSlot<Captured>) -> bool {
+    // Due time of the next capture, the rate holds as long as capturing is
+    // faster than the interval
+    let mut next = Instant::now();
+
+    while !slot.is_closed() {
+      let is_running = self.tracking.lock().mode == WatcherMode::Running;
+
+      let command = if is_running {
+        match commands.recv_timeout(next.saturating_duration_since(Instant::now())) {
+          Ok(command) => Some(command),
+          Err(RecvTimeoutError::Timeout) => None,
+          Err(RecvTimeoutError::Disconnected) => return true,
+        }
+      } else {
+        match commands.recv() {
+          Ok(command) => Some(command),
+          Err(_) => return true,
+        }
+      };
+
+      match command {
+        Some(WatcherCommand::Shutdown) => return true,
+        // Capture right away instead of after a whole interval
+        Some(WatcherCommand::Start) => self.apply(WatcherCommand::Start),
+        Some(command) => {
+          self.apply(command);
+          slot.clear();
+          continue;
+        }
+        None => {}
+      }
+
+      let interval = self.state.read().config.settings.watcher.interval_ms;
+      next = Instant::now() + Duration::from_millis(interval);
+
+      // A frame that can't be taken doesn't stop the watcher
+      match self.capture() {
+        Ok(Some(captured)) => {
+          if slot.put(captured) {
+            self.tracking.lock().dropped += 1;
+          }
+        }
+        Ok(None) => {}
+        Err(err) => {
+          eprintln!("capture: {}", err);
+          self.events.publish(WatcherEvent::Error(err.to_string()));
+        }
+      }
     }
-    let window_id = state.window_capture.as_ref().unwrap().window.id();
-    drop(state);
 
-    println!("check: capture");
-    let capture = capture::take_one(window_id);
-    println!("check: capture: {:?}", capture.is_some());
+    return false;
+  }
 
-    let mut state = self.state.write();
+  /// Analyzes the frames of the capture thread until the slot is closed
+  fn analyze_frames(&self, slot: &FrameSlot<Captured>) {
+    let _close = CloseOnDrop(slot);
+    while let Some(captured) = slot.take() {
+      self.analyze(captured);
+    }
+  }
 
-    if capture.is_none() {
-      state.window_capture = None;
-      state.interface = Interface::WindowSelect;
-      return;
+  fn apply(&self, command: WatcherCommand) {
+    let mut tracking = self.tracking.lock();
+    let mode = match command {
+      WatcherCommand::Start => {
+        tracking.idle.resume(self.started.elapsed());
+        WatcherMode::Running
+      }
+      WatcherCommand::Stop => {
+        // Publish the next status even if it's the same as before stopping,
+        // the mode is kept for the comparison below
+        *tracking = Tracking { mode: tracking.mode, civ: tracking.civ.take(), ..Default::default() };
+        WatcherMode::Stopped
+      }
+      WatcherCommand::Pause if tracking.mode == WatcherMode::Running => {
+        tracking.idle.pause(self.started.elapsed());
+        WatcherMode::Paused
+      }
+      WatcherCommand::Pause | WatcherCommand::Shutdown => tracking.mode,
+    };
+    if tracking.mode != mode {
+      tracking.mode = mode;
+      self.events.publish(WatcherEvent::ModeChanged(mode));
     }
-    println!("check: captured");
-    println!("check: {}", state.is_watching);
 
-    if state.is_watching == false {
-      return;
+    if matches!(command, WatcherCommand::Stop | WatcherCommand::Pause) {
+      if let Some(audio) = self.audio.as_ref() {
+        audio.stop(self.started.elapsed());
+      }
+    }
+  }
+
+  fn send(&self, command: WatcherCommand) {
+    match self.commands.as_ref() {
+      Some(commands) => {
+        let _ = commands.send(command);
+      }
+      None => self.apply(command),
+    }
+  }
+
+  pub fn start(&self) {
+    self.send(WatcherCommand::Start);
+  }
+
+  pub fn stop(&self) {
+    self.send(WatcherCommand::Stop);
+  }
+
+  pub fn pause(&self) {
+    self.send(WatcherCommand::Pause);
+  }
+
+  /// Receives the events published from now on
+  pub fn subscribe(&self) -> Receiver<WatcherEvent> {
+    self.events.subscribe()
+  }
+
+  /// Latest frame captured since the last call, if any
+  pub fn take_frame(&self) -> Option<Arc<image::RgbaImage>> {
+    self.frame.try_take()
+  }
+
+  /// Ends the watcher thread, waits for the frame being checked if any
+  pub fn shutdown(&self) {
+    self.send(WatcherCommand::Shutdown);
+    if let Some(supervisor) = self.supervisor.lock().take() {
+      let _ = supervisor.join();
     }
+  }
+
+  /// Creates a watcher that isn't driven by a thread: call `check()` until
+  /// `has_source()` returns false.
+  pub fn headless(state: Arc<RwLock<State>>, source: S) -> Self {
+    Watcher {
+      state,
+      source: Arc::new(Mutex::new(Some(source))),
+      tracking: Default::default(),
+      events: Default::default(),
+      detector: Default::default(),
+      frame: Default::default(),
+      started: Instant::now(),
+      audio: None,
+      commands: None,
+      supervisor: Default::default(),
+    }
+  }
+
+  pub fn set_source(&self, source: S) {
+    *self.source.lock() = Some(source);
+  }
+
+  /// Sends the alerts to `audio` instead of the default output device
+  pub fn set_audio(&mut self, audio: Arc<dyn AudioSink>) {
+    self.audio = Some(audio);
+  }
+
+  pub fn has_source(&self) -> bool {
+    self.source.lock().is_some()
+  }
+
+  /// Plays an alert stage right away, e.g. while tuning it
+  pub fn preview(&self, stage: &AlertStage) {
+    if let Some(audio) = self.audio.as_ref() {
+      audio.play(cue(stage), self.started.elapsed());
+    }
+  }
+
+  /// Captures and analyzes the next frame of the source in one go, while
+  /// started. Errors are about this frame only, the source is kept for the
+  /// next check.
+  pub fn check(&self) -> Result<Option<Sample>> {
+    let Some(captured) = self.capture()? else {
+      return Ok(None);
+    };
+    return Ok(self.analyze(captured));
+  }
+
+  /// Takes the next frame of the source, while started
+  fn capture(&self) -> Result<Option<Captured>> {
+    if self.tracking.lock().mode != WatcherMode::Running {
+      return Ok(None);
+    }
+
+    let started = Instant::now();
+    let mut source = self.source.lock();
+    let frame = match source.as_mut() {
+      Some(source) => source.next_frame()?,
+      None => return Ok(None),
+    };
+
+    let Some(frame) = frame else {
+      *source = None;
+      self.events.publish(WatcherEvent::WindowLost);
+      return Ok(None);
+    };
+    drop(source);
+
+    let data = Arc::new(frame.data);
+    self.frame.put(data.clone());
+    self.events.publish(WatcherEvent::FrameCaptured);
+
+    return Ok(Some(Captured {
+      frame: data,
+      timestamp: frame.timestamp,
+      started,
+      capture: started.elapsed(),
+    }));
+  }
+
+  /// Looks for the villager in a captured frame and plays the alerts, while
+  /// started
+  fn analyze(&self, captured: Captured) -> Option<Sample> {
+    let analysis_started = Instant::now();
+    let civ = {
+      let tracking = self.tracking.lock();
+      if tracking.mode != WatcherMode::Running {
+        return None;
+      }
+      tracking.civ.clone()
+    };
+
+    let mut detector = self.detector.lock();
+    let alert_settings = {
+      let state = self.state.read();
+      if !detector.as_ref().is_some_and(|detector| state.config.is_detected_by(detector)) {
+        let mut rebuilt = MultiDetector::new(state.config.profiles());
+        if let Some(civ) = civ.as_ref() {
+          rebuilt.lock(civ);
+        }
+        *detector = Some(rebuilt);
+      }
+      state.config.alert.clone()
+    };
+    let detector = detector.as_mut().unwrap();
+
+    let detection = detector.detect(&captured.frame);
+    let detected = Instant::now();
+    let detection_timings = detector.timings();
+
+    let civ = detector.locked().map(str::to_owned);
+    let mut tracking = self.tracking.lock();
+    // Paused or stopped during the detection
+    if tracking.mode != WatcherMode::Running {
+      return None;
+    }
+    if tracking.status.as_ref() != Some(&detection.status) || tracking.civ != civ {
+      tracking.status = Some(detection.status.clone());
+      tracking.civ = civ.clone();
+      self.events.publish(WatcherEvent::DetectionChanged { detection: detection.clone(), civ: civ.clone() });
+    }
+
+    // Recorded frames carry their own clock
+    let now = captured.timestamp.unwrap_or_else(|| captured.started.duration_since(self.started));
+    let was_alerting = tracking.idle.stage().is_some();
+    let alert = tracking.idle.update(&detection.status, &alert_settings, now);
+    let dropped = tracking.dropped;
+    drop(tracking);
+
+    let alert_started = Instant::now();
+    if let Some(audio) = self.audio.as_ref() {
+      // Also when the panel is hidden, e.g. by a menu, the tracker starts
+      // over then
+      if was_alerting && detection.status != DetectionStatus::NotQueued {
+        audio.stop(now);
+      }
+      if let Some(stage) = alert.as_ref() {
+        audio.play(cue(stage), now);
+      }
+    }
+    if let Some(stage) = alert.as_ref() {
+      self.events.publish(WatcherEvent::AlertFired { stage: stage.name.clone() });
+    }
+
+    let timings = Timings {
+      capture: captured.capture,
+      queued: analysis_started.saturating_duration_since(captured.started + captured.capture),
+      crop: detection_timings.crop,
+      luma: detection_timings.luma,
+      matching: detection_timings.matching,
+      detection: detected.duration_since(analysis_started),
+      alert: alert_started.elapsed(),
+      total: captured.started.elapsed(),
+    };
+    self.events.publish(WatcherEvent::FrameAnalyzed { timings, dropped });
 
-    let capture = capture.unwrap();
+    return Some(Sample {
+      timestamp: captured.timestamp,
+      detection,
+      civ,
+      alert: alert.map(|stage| stage.name),
+      timings,
+    });
+  }
+}
+
+fn cue(stage: &AlertStage) -> Cue {
+  let waveform = match stage.sound.as_ref().map(|path| sound::load_sound(path)) {
+    Some(Ok(samples)) => Waveform::Samples(samples),
+    Some(Err(err)) => {
+      eprintln!("Could not load alert sound, playing the tone instead: {}", err);
+      Waveform::Tone(stage.note)
+    }
+    None => Waveform::Tone(stage.note),
+  };
+
+  return Cue {
+    waveform,
+    volume: stage.volume,
+    duration: Duration::from_millis(stage.duration_ms),
+  };
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::VecDeque;
+  use image::RgbaImage;
+  use aoe4_villager_enforcer::detector::SearchRegion;
+  use aoe4_villager_enforcer::source::Frame;
+
+  use super::*;
+  use crate::config::{Config, Needle};
+  use crate::sound::{AudioCommand, RecordingSink};
+  use crate::test_support::{self, background, needle};
+
+  fn stage(name: &str, delay_ms: u64, repeat_ms: u64) -> AlertStage {
+    AlertStage {
+      name: name.to_owned(),
+      delay_ms,
+      repeat_ms,
+      note: 880.0,
+      duration_ms: 200,
+      volume: 0.5,
+      sound: None,
+    }
+  }
 
-    // Slice the haystack a bit to make it faster
-    let x = 0;
-    let y = capture.data.height() / 2;
-    let width = capture.data.width() / 4;
-    let height = state.config.y_max - y;
-    let haystack_image = capture.data.view(x, y, width, height);
-    let haystack_image = DynamicImage::ImageRgba8(haystack_image.to_image()).to_luma8();
+  fn settings(misses: u32, grace_ms: u64) -> AlertSettings {
+    AlertSettings {
+      misses,
+      grace_ms,
+      stages: vec![stage("warning", 0, 3000), stage("alarm", 5000, 1000)],
+    }
+  }
 
-    let needle_image = state.config.data.as_ref().unwrap().clone();
+  /// Feeds `status` at `ms` on the fake clock, returns the stage played
+  fn update(tracker: &mut IdleTracker, status: DetectionStatus, settings: &AlertSettings, ms: u64) -> Option<String> {
+    return tracker
+      .update(&status, settings, Duration::from_millis(ms))
+      .map(|stage| stage.name);
+  }
 
-    let mut finder = SubImageFinderState::new();
+  fn unknown() -> DetectionStatus {
+    DetectionStatus::Unknown { reason: "menu".to_owned() }
+  }
 
-    println!("check: find");
+  #[test]
+  fn waits_for_the_misses() {
+    let settings = settings(3, 0);
+    let mut tracker = IdleTracker::default();
+    assert_eq!(update(&mut tracker, DetectionStatus::NotQueued, &settings, 0), None);
+    assert_eq!(update(&mut tracker, DetectionStatus::NotQueued, &settings, 250), None);
+    assert_eq!(update(&mut tracker, DetectionStatus::NotQueued, &settings, 500).as_deref(), Some("warning"));
+  }
 
-    // find_subimage_positions() is a long operation
-    drop(state);
+  #[test]
+  fn waits_for_the_grace_period() {
+    let settings = settings(1, 1000);
+    let mut tracker = IdleTracker::default();
+    assert_eq!(update(&mut tracker, DetectionStatus::NotQueued, &settings, 0), None);
+    assert_eq!(update(&mut tracker, DetectionStatus::NotQueued, &settings, 999), None);
+    assert_eq!(update(&mut tracker, DetectionStatus::NotQueued, &settings, 1000).as_deref(), Some("warning"));
+  }
 
-    println!("check: did_run");
+  #[test]
+  fn starts_over_once_queued() {
+    let settings = settings(2, 0);
+    let mut tracker = IdleTracker::default();
+    update(&mut tracker, DetectionStatus::NotQueued, &settings, 0);
+    assert_eq!(update(&mut tracker, DetectionStatus::NotQueued, &settings, 250).as_deref(), Some("warning"));
+    assert_eq!(tracker.stage(), Some(0));
 
-    // These are (x, y, distance) where x and y are the position within the larger image
-    // and distance is the distance value, where a smaller distance means a more precise match
-    let positions =
-      finder.find_subimage_positions(
-        (&haystack_image.as_raw(), haystack_image.width() as usize, haystack_image.height() as usize),
-        (&needle_image.as_raw(), needle_image.width() as usize, needle_image.height() as usize),
-        1
-      );
+    assert_eq!(update(&mut tracker, DetectionStatus::Queued, &settings, 500), None);
+    assert_eq!(tracker.stage(), None);
+    assert_eq!(update(&mut tracker, DetectionStatus::NotQueued, &settings, 750), None);
+    assert_eq!(update(&mut tracker, DetectionStatus::NotQueued, &settings, 1000).as_deref(), Some("warning"));
+  }
 
-    let position: Option<&(usize, usize, f32)> =
-      positions
-        .iter()
-        .min_by(|(_, _, dist), (_, _, dist2)| dist.partial_cmp(dist2).unwrap());
+  #[test]
+  fn starts_over_on_unknown_frames() {
+    let settings = settings(2, 0);
+    let mut tracker = IdleTracker::default();
+    update(&mut tracker, DetectionStatus::NotQueued, &settings, 0);
+    update(&mut tracker, DetectionStatus::NotQueued, &settings, 250);
 
-    println!("FOUND: {:?}", &position);
-    println!("POSITIONS: {:?}", positions);
+    assert_eq!(update(&mut tracker, unknown(), &settings, 500), None);
+    assert_eq!(tracker.stage(), None);
+    assert_eq!(update(&mut tracker, DetectionStatus::NotQueued, &settings, 750), None);
+  }
 
-    let mut state = self.state.write();
+  #[test]
+  fn escalates_through_the_stages() {
+    let settings = settings(1, 0);
+    let mut tracker = IdleTracker::default();
+    assert_eq!(update(&mut tracker, DetectionStatus::NotQueued, &settings, 1000).as_deref(), Some("warning"));
+    assert_eq!(update(&mut tracker, DetectionStatus::NotQueued, &settings, 3000), None);
+    assert_eq!(update(&mut tracker, DetectionStatus::NotQueued, &settings, 4000).as_deref(), Some("warning"));
+    // The next stage plays right away, even if the previous one isn't due
+    assert_eq!(update(&mut tracker, DetectionStatus::NotQueued, &settings, 6000).as_deref(), Some("alarm"));
+    assert_eq!(tracker.stage(), Some(1));
+  }
 
-    state.window_capture = Some(capture);
-    state.is_queued = position.is_some();
+  #[test]
+  fn repeats_every_repeat_ms() {
+    let settings = settings(1, 0);
+    let mut tracker = IdleTracker::default();
+    let played = (0..=4000)
+      .step_by(250)
+      .filter_map(|ms| update(&mut tracker, DetectionStatus::NotQueued, &settings, ms).map(|_| ms))
+      .collect::<Vec<_>>();
+    assert_eq!(played, vec![0, 3000]);
 
-    if state.is_queued == false {
-      println!("check: play_tone");
-      sound::play_tone(NOTE, Duration::from_millis(500));
+    // The alarm repeats every second
+    let played = (4250..=8000)
+      .step_by(250)
+      .filter_map(|ms| update(&mut tracker, DetectionStatus::NotQueued, &settings, ms).map(|_| ms))
+      .collect::<Vec<_>>();
+    assert_eq!(played, vec![5000, 6000, 7000, 8000]);
+  }
+
+  #[test]
+  fn picks_the_stage_by_delay() {
+    let mut settings = settings(1, 0);
+    settings.stages.reverse();
+    let mut tracker = IdleTracker::default();
+    assert_eq!(update(&mut tracker, DetectionStatus::NotQueued, &settings, 0).as_deref(), Some("warning"));
+    assert_eq!(update(&mut tracker, DetectionStatus::NotQueued, &settings, 5000).as_deref(), Some("alarm"));
+  }
+
+  #[test]
+  fn paused_time_is_not_idle() {
+    let settings = settings(1, 1000);
+    let mut tracker = IdleTracker::default();
+    assert_eq!(update(&mut tracker, DetectionStatus::NotQueued, &settings, 0), None);
+    tracker.pause(Duration::from_millis(500));
+    tracker.resume(Duration::from_millis(10_500));
+    assert_eq!(update(&mut tracker, DetectionStatus::NotQueued, &settings, 10_999), None);
+    assert_eq!(update(&mut tracker, DetectionStatus::NotQueued, &settings, 11_000).as_deref(), Some("warning"));
+
+    // The repeats and the escalation carry on from where they were too
+    tracker.pause(Duration::from_millis(11_500));
+    tracker.resume(Duration::from_millis(20_000));
+    assert_eq!(update(&mut tracker, DetectionStatus::NotQueued, &settings, 22_499), None);
+    assert_eq!(update(&mut tracker, DetectionStatus::NotQueued, &settings, 22_500).as_deref(), Some("warning"));
+    assert_eq!(update(&mut tracker, DetectionStatus::NotQueued, &settings, 24_499), None);
+    assert_eq!(update(&mut tracker, DetectionStatus::NotQueued, &settings, 24_500).as_deref(), Some("alarm"));
+  }
+
+  /// Recorded frames, played back in order
+  struct Frames(VecDeque<Frame>);
+
+  impl FrameSource for Frames {
+    fn next_frame(&mut self) -> Result<Option<Frame>> {
+      return Ok(self.0.pop_front());
     }
   }
+
+  /// Frame at `ms`, with the needle in the production panel if queued
+  fn frame(ms: u64, is_queued: bool) -> Frame {
+    let data = if is_queued { test_support::frame(&needle(3)) } else { background() };
+    return Frame { data, timestamp: Some(Duration::from_millis(ms)) };
+  }
+
+  /// Runs a headless watcher on `frames`, returns what it sent to the audio
+  fn record(frames: Vec<Frame>) -> Vec<(u64, AudioCommand)> {
+    let config = Config {
+      needles: vec![Needle { civ: "default".to_owned(), data: needle(3) }],
+      search: SearchRegion { left: 0.0, top: 0.5, width: 0.5, height: 0.5 },
+      alert: settings(1, 0),
+      ..Default::default()
+    };
+    let state = Arc::new(RwLock::new(State::new(config)));
+    let mut watcher = Watcher::headless(state, Frames(frames.into()));
+    let recording = Arc::new(RecordingSink::new());
+    watcher.set_audio(recording.clone());
+    watcher.start();
+
+    while watcher.has_source() {
+      watcher.check().unwrap();
+    }
+
+    return recording
+      .events()
+      .into_iter()
+      .map(|event| (event.at.as_millis() as u64, event.command))
+      .collect();
+  }
+
+  #[test]
+  fn plays_the_alert_until_queued() {
+    let events = record(vec![
+      frame(0, true),
+      frame(500, false),
+      frame(1000, false),
+      frame(1500, true),
+    ]);
+
+    assert_eq!(events.len(), 2, "{:?}", events);
+    assert!(matches!(&events[0], (500, AudioCommand::Play(cue)) if cue.volume == 0.5));
+    assert!(matches!(events[1], (1500, AudioCommand::Stop)));
+  }
+
+  #[test]
+  fn stops_the_alert_when_the_panel_is_hidden() {
+    let menu = Frame { data: RgbaImage::new(200, 200), timestamp: Some(Duration::from_millis(1000)) };
+    let events = record(vec![frame(0, true), frame(500, false), menu]);
+
+    assert_eq!(events.len(), 2, "{:?}", events);
+    assert!(matches!(events[1], (1000, AudioCommand::Stop)));
+  }
 }
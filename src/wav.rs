@@ -0,0 +1,236 @@
+use std::io::{Error, ErrorKind, Result};
+
+const FORMAT_PCM: u16 = 1;
+const FORMAT_FLOAT: u16 = 3;
+const FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+
+/// Decoded WAV file
+pub struct Wav {
+  pub sample_rate: u32,
+  pub channels: u16,
+  /// Interleaved samples, from -1.0 to 1.0
+  pub samples: Vec<f32>,
+}
+
+impl Wav {
+  /// Mixes the channels down and resamples to `sample_rate`
+  pub fn to_mono(&self, sample_rate: u32) -> Vec<f32> {
+    let channels = self.channels as usize;
+    let mono = self.samples
+      .chunks_exact(channels)
+      .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+      .collect::<Vec<_>>();
+
+    if self.sample_rate == sample_rate || mono.is_empty() {
+      return mono;
+    }
+
+    // Linear interpolation is good enough for alert sounds
+    let ratio = self.sample_rate as f64 / sample_rate as f64;
+    let length = (mono.len() as f64 / ratio) as usize;
+    return (0..length)
+      .map(|i| {
+        let position = i as f64 * ratio;
+        let index = position as usize;
+        let t = (position - index as f64) as f32;
+        let a = mono[index];
+        let b = mono.get(index + 1).copied().unwrap_or(a);
+        a + (b - a) * t
+      })
+      .collect();
+  }
+}
+
+/// Decodes a RIFF WAV file: 8, 16, 24 or 32 bits integer PCM, or 32 bits float
+pub fn decode(bytes: &[u8]) -> Result<Wav> {
+  if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+    return Err(invalid("not a WAV file"));
+  }
+
+  let mut format = None;
+  let mut data = None;
+
+  let mut offset = 12;
+  while offset + 8 <= bytes.len() {
+    let id = &bytes[offset..offset + 4];
+    let size = u32_at(bytes, offset + 4) as usize;
+    let start = offset + 8;
+    let end = start.saturating_add(size).min(bytes.len());
+
+    match id {
+      b"fmt " => format = Some(&bytes[start..end]),
+      b"data" => data = Some(&bytes[start..end]),
+      _ => {}
+    }
+
+    // Chunks are padded to an even size
+    offset = start.saturating_add(size).saturating_add(size % 2);
+  }
+
+  let format = format.ok_or_else(|| invalid("missing fmt chunk"))?;
+  let data = data.ok_or_else(|| invalid("missing data chunk"))?;
+
+  if format.len() < 16 {
+    return Err(invalid("fmt chunk too short"));
+  }
+
+  let mut audio_format = u16_at(format, 0);
+  let channels = u16_at(format, 2);
+  let sample_rate = u32_at(format, 4);
+  let bits = u16_at(format, 14);
+
+  if audio_format == FORMAT_EXTENSIBLE {
+    if format.len() < 26 {
+      return Err(invalid("fmt chunk too short"));
+    }
+    // The sub-format GUID starts with the actual format code
+    audio_format = u16_at(format, 24);
+  }
+
+  if channels == 0 || sample_rate == 0 {
+    return Err(invalid("invalid fmt chunk"));
+  }
+
+  let samples = match (audio_format, bits) {
+    (FORMAT_PCM, 8) => data.iter().map(|v| (*v as f32 - 128.0) / 128.0).collect(),
+    (FORMAT_PCM, 16) => data
+      .chunks_exact(2)
+      .map(|v| i16::from_le_bytes([v[0], v[1]]) as f32 / 32768.0)
+      .collect(),
+    (FORMAT_PCM, 24) => data
+      .chunks_exact(3)
+      .map(|v| (i32::from_le_bytes([0, v[0], v[1], v[2]]) >> 8) as f32 / 8_388_608.0)
+      .collect(),
+    (FORMAT_PCM, 32) => data
+      .chunks_exact(4)
+      .map(|v| i32::from_le_bytes([v[0], v[1], v[2], v[3]]) as f32 / 2_147_483_648.0)
+      .collect(),
+    (FORMAT_FLOAT, 32) => data
+      .chunks_exact(4)
+      .map(|v| f32::from_le_bytes([v[0], v[1], v[2], v[3]]))
+      .collect(),
+    _ => return Err(invalid(&format!("unsupported format {} with {} bits", audio_format, bits))),
+  };
+
+  return Ok(Wav {
+    sample_rate,
+    channels,
+    samples,
+  });
+}
+
+/// Encodes interleaved samples as a 16 bits PCM WAV file
+pub fn encode(sample_rate: u32, channels: u16, samples: &[f32]) -> Vec<u8> {
+  let data_size = (samples.len() * 2) as u32;
+  let block_align = channels * 2;
+
+  let mut bytes = Vec::with_capacity(44 + data_size as usize);
+  bytes.extend_from_slice(b"RIFF");
+  bytes.extend_from_slice(&(36 + data_size).to_le_bytes());
+  bytes.extend_from_slice(b"WAVE");
+
+  bytes.extend_from_slice(b"fmt ");
+  bytes.extend_from_slice(&16u32.to_le_bytes());
+  bytes.extend_from_slice(&FORMAT_PCM.to_le_bytes());
+  bytes.extend_from_slice(&channels.to_le_bytes());
+  bytes.extend_from_slice(&sample_rate.to_le_bytes());
+  bytes.extend_from_slice(&(sample_rate * block_align as u32).to_le_bytes());
+  bytes.extend_from_slice(&block_align.to_le_bytes());
+  bytes.extend_from_slice(&16u16.to_le_bytes());
+
+  bytes.extend_from_slice(b"data");
+  bytes.extend_from_slice(&data_size.to_le_bytes());
+  for sample in samples {
+    let value = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+    bytes.extend_from_slice(&value.to_le_bytes());
+  }
+
+  return bytes;
+}
+
+fn invalid(message: &str) -> Error {
+  Error::new(ErrorKind::InvalidData, message.to_owned())
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+  u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+  u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// WAV file with a fmt chunk of `format` and `data`
+  fn wav(format: u16, channels: u16, sample_rate: u32, bits: u16, data: &[u8]) -> Vec<u8> {
+    let mut bytes = vec![];
+    bytes.extend_from_slice(b"RIFF");
+    bytes.extend_from_slice(&(36 + data.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(b"WAVE");
+    bytes.extend_from_slice(b"fmt ");
+    bytes.extend_from_slice(&16u32.to_le_bytes());
+    bytes.extend_from_slice(&format.to_le_bytes());
+    bytes.extend_from_slice(&channels.to_le_bytes());
+    bytes.extend_from_slice(&sample_rate.to_le_bytes());
+    bytes.extend_from_slice(&(sample_rate * (channels * bits / 8) as u32).to_le_bytes());
+    bytes.extend_from_slice(&(channels * bits / 8).to_le_bytes());
+    bytes.extend_from_slice(&bits.to_le_bytes());
+    bytes.extend_from_slice(b"data");
+    bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(data);
+    return bytes;
+  }
+
+  #[test]
+  fn round_trip() {
+    let samples = [0.0, 0.5, -0.5, 1.0, -1.0, 0.25];
+    let wav = decode(&encode(22050, 2, &samples)).unwrap();
+
+    assert_eq!(wav.sample_rate, 22050);
+    assert_eq!(wav.channels, 2);
+    assert_eq!(wav.samples.len(), samples.len());
+    for (decoded, sample) in wav.samples.iter().zip(samples) {
+      assert!((decoded - sample).abs() < 1.0 / 16384.0, "{} != {}", decoded, sample);
+    }
+  }
+
+  #[test]
+  fn decodes_integer_samples() {
+    let data = [0i16, 16384, -32768].iter().flat_map(|v| v.to_le_bytes()).collect::<Vec<_>>();
+    let decoded = decode(&wav(FORMAT_PCM, 1, 8000, 16, &data)).unwrap();
+    assert_eq!(decoded.sample_rate, 8000);
+    assert_eq!(decoded.channels, 1);
+    assert_eq!(decoded.samples, vec![0.0, 0.5, -1.0]);
+
+    let decoded = decode(&wav(FORMAT_PCM, 1, 8000, 8, &[128, 192, 0])).unwrap();
+    assert_eq!(decoded.samples, vec![0.0, 0.5, -1.0]);
+  }
+
+  #[test]
+  fn decodes_float_samples() {
+    let data = [0.75f32, -0.25].iter().flat_map(|v| v.to_le_bytes()).collect::<Vec<_>>();
+    let decoded = decode(&wav(FORMAT_FLOAT, 1, 8000, 32, &data)).unwrap();
+    assert_eq!(decoded.samples, vec![0.75, -0.25]);
+  }
+
+  #[test]
+  fn rejects_other_files() {
+    assert!(decode(b"not a wav file").is_err());
+    assert!(decode(b"RIFF\0\0\0\0WAVE").is_err());
+    assert!(decode(&wav(FORMAT_PCM, 1, 8000, 12, &[0, 0])).is_err());
+  }
+
+  #[test]
+  fn mixes_down_and_resamples() {
+    let wav = Wav {
+      sample_rate: 2,
+      channels: 2,
+      samples: vec![1.0, 0.0, 0.0, 0.0],
+    };
+    assert_eq!(wav.to_mono(2), vec![0.5, 0.0]);
+    assert_eq!(wav.to_mono(4), vec![0.5, 0.25, 0.0, 0.0]);
+  }
+}